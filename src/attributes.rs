@@ -0,0 +1,170 @@
+//! Attributes of source files, from `statx`, which reports more than `stat`: the time a file was
+//! created, flags such as immutability, and the mount it is on.
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use fuse::FileType;
+use libc::{c_int, AT_EMPTY_PATH, AT_FDCWD, AT_STATX_SYNC_AS_STAT, AT_SYMLINK_NOFOLLOW, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFSOCK};
+use libc::{STATX_ATTR_APPEND, STATX_ATTR_IMMUTABLE, STATX_BASIC_STATS, STATX_BTIME, STATX_MNT_ID};
+use time::Timespec;
+
+/// Attributes of a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    /// Device holding the file.
+    pub dev: u64,
+    /// Inode number on that device.
+    pub ino: u64,
+    /// File type and permission bits.
+    pub mode: u32,
+    /// Number of hard links.
+    pub nlink: u32,
+    /// Owner.
+    pub uid: u32,
+    /// Group.
+    pub gid: u32,
+    /// Device, for a device node.
+    pub rdev: u64,
+    /// Size in bytes.
+    pub size: u64,
+    /// Number of 512-byte blocks allocated.
+    pub blocks: u64,
+    /// Time of last access.
+    pub atime: Timespec,
+    /// Time of last modification.
+    pub mtime: Timespec,
+    /// Time of last status change.
+    pub ctime: Timespec,
+    /// Time of creation, if the filesystem records it.
+    pub btime: Option<Timespec>,
+    /// Whether the file cannot be changed, or deleted, even by root (`chattr +i`).
+    pub immutable: bool,
+    /// Whether the file can only be appended to, and so cannot be deleted (`chattr +a`).
+    pub append: bool,
+    /// Mount holding the file, as in `/proc/self/mountinfo` (Linux 5.8 or later).
+    pub mount_id: Option<u64>,
+}
+
+fn timespec(timestamp: libc::statx_timestamp) -> Timespec {
+    Timespec { sec: timestamp.tv_sec, nsec: timestamp.tv_nsec as i32 }
+}
+
+fn statx(dir: c_int, path: &Path, flags: c_int) -> io::Result<Attributes> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buffer = MaybeUninit::<libc::statx>::zeroed();
+    let mask = STATX_BASIC_STATS | STATX_BTIME | STATX_MNT_ID;
+    if unsafe { libc::statx(dir, path.as_ptr(), flags | AT_STATX_SYNC_AS_STAT, mask, buffer.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let stx = unsafe { buffer.assume_init() };
+    let attribute = |flag: c_int| stx.stx_attributes & flag as u64 != 0;
+    Ok(Attributes {
+        dev: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+        ino: stx.stx_ino,
+        mode: u32::from(stx.stx_mode),
+        nlink: stx.stx_nlink,
+        uid: stx.stx_uid,
+        gid: stx.stx_gid,
+        rdev: libc::makedev(stx.stx_rdev_major, stx.stx_rdev_minor),
+        size: stx.stx_size,
+        blocks: stx.stx_blocks,
+        atime: timespec(stx.stx_atime),
+        mtime: timespec(stx.stx_mtime),
+        ctime: timespec(stx.stx_ctime),
+        btime: match stx.stx_mask & STATX_BTIME {
+            0 => None,
+            _ => Some(timespec(stx.stx_btime))
+        },
+        immutable: attribute(STATX_ATTR_IMMUTABLE),
+        append: attribute(STATX_ATTR_APPEND),
+        mount_id: match stx.stx_mask & STATX_MNT_ID {
+            0 => None,
+            _ => Some(stx.stx_mnt_id)
+        },
+    })
+}
+
+impl Attributes {
+    /// Attributes of the file at `path`, or of the link if it is a symbolic link.
+    pub fn of(path: &Path) -> io::Result<Attributes> {
+        statx(AT_FDCWD, path, AT_SYMLINK_NOFOLLOW)
+    }
+
+    /// Attributes of the file `name` in the directory `dir`, or of the link if it is one.
+    pub(crate) fn at(dir: &File, name: &Path) -> io::Result<Attributes> {
+        statx(dir.as_raw_fd(), name, AT_SYMLINK_NOFOLLOW)
+    }
+
+    /// Attributes of the open file `file`, which may since have been removed.
+    pub(crate) fn of_file(file: &File) -> io::Result<Attributes> {
+        statx(file.as_raw_fd(), Path::new(""), AT_EMPTY_PATH)
+    }
+
+    /// Identity of the file in the source tree.
+    pub(crate) fn id(&self) -> (u64, u64) {
+        (self.dev, self.ino)
+    }
+
+    /// Whether the file is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    /// Whether the file is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// Type of the file, as presented through the mount.
+    pub(crate) fn kind(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::RegularFile
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::linux::fs::MetadataExt;
+
+    #[test]
+    fn matches_stat() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "contents").unwrap();
+        let attr = Attributes::of(&file).unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+        assert_eq!(attr.id(), (metadata.st_dev(), metadata.st_ino()));
+        assert_eq!((attr.mode, attr.size, attr.nlink), (metadata.st_mode(), 8, 1));
+        assert_eq!(attr.mtime, Timespec { sec: metadata.st_mtime(), nsec: metadata.st_mtime_nsec() as i32 });
+        assert_eq!(attr.kind(), FileType::RegularFile);
+        assert!(!attr.immutable && !attr.append);
+        assert!(attr.mount_id.is_some());
+        let open = File::open(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(Attributes::of_file(&open).unwrap(), Attributes { nlink: 0, ctime: Attributes::of_file(&open).unwrap().ctime, ..attr });
+    }
+
+    #[test]
+    fn symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("link")).unwrap();
+        assert!(Attributes::of(&dir.path().join("link")).unwrap().is_symlink());
+        assert!(Attributes::of_file(&File::open(dir.path().join("link")).unwrap()).unwrap().is_dir());
+        assert!(Attributes::at(&File::open(dir.path()).unwrap(), Path::new("link")).unwrap().is_symlink());
+        assert_eq!(Attributes::of(Path::new("/dev/null")).unwrap().kind(), FileType::CharDevice);
+    }
+}
@@ -0,0 +1,230 @@
+//! Access to paths within a source directory which cannot lead outside it, even if a directory
+//! on the way is replaced by a symbolic link between looking a path up and acting on it.
+//!
+//! Paths are resolved by the kernel with `openat2(RESOLVE_BENEATH)`, which fails with `EXDEV`
+//! on leaving the directory, and with `RESOLVE_NO_SYMLINKS`, which fails with `ELOOP` on meeting
+//! a link, unless links are followed. Files are examined and removed from their parent, with
+//! `statx`, `readlinkat` or `unlinkat`, so the name itself is never followed.
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+
+use libc::{c_char, c_int, c_long, AT_REMOVEDIR, O_CLOEXEC, O_DIRECTORY, O_PATH, O_RDONLY, RESOLVE_BENEATH, RESOLVE_NO_MAGICLINKS, RESOLVE_NO_SYMLINKS};
+
+use crate::attributes::Attributes;
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Open `path` relative to the directory `dir`, without leaving it.
+fn openat2(dir: &File, path: &Path, flags: c_int, follow: bool) -> io::Result<File> {
+    let path = match path.as_os_str().is_empty() {
+        true => cstring(Path::new("."))?,
+        false => cstring(path)?
+    };
+    let mut how: libc::open_how = unsafe { zeroed() };
+    how.flags = (flags | O_CLOEXEC) as u64;
+    how.resolve = RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS | if follow { 0 } else { RESOLVE_NO_SYMLINKS };
+    let fd = unsafe {
+        libc::syscall(libc::SYS_openat2, dir.as_raw_fd() as c_long, path.as_ptr(), &how as *const libc::open_how, size_of::<libc::open_how>())
+    };
+    match fd {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd as c_int) })
+    }
+}
+
+fn open_root(root: &Path) -> io::Result<File> {
+    let root = cstring(root)?;
+    match unsafe { libc::open(root.as_ptr(), O_PATH | O_DIRECTORY | O_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) })
+    }
+}
+
+/// Directory holding `path`, relative to `root`, and the name of `path` within it: `.` for `root`
+/// itself. Symbolic links leading to the directory are only followed if `follow`.
+fn at<'a>(root: &Path, path: &'a Path, follow: bool) -> io::Result<(File, &'a Path)> {
+    let root = open_root(root)?;
+    match path.file_name() {
+        Some(name) => Ok((openat2(&root, path.parent().unwrap_or_else(|| Path::new("")), O_PATH | O_DIRECTORY, follow)?, Path::new(name))),
+        None => Ok((root, Path::new(".")))
+    }
+}
+
+/// Open `path`, relative to `root`, with `flags`; symbolic links on the way are only followed
+/// if `follow`, and then only while they stay within `root`.
+pub(crate) fn open(root: &Path, path: &Path, flags: c_int, follow: bool) -> io::Result<File> {
+    openat2(&open_root(root)?, path, flags, follow)
+}
+
+/// Remove the file, or empty directory if `directory`, at `path` relative to `root`; symbolic
+/// links leading to its parent are only followed if `follow`, and then only within `root`.
+pub(crate) fn remove(root: &Path, path: &Path, directory: bool, follow: bool) -> io::Result<()> {
+    if path.file_name().is_none() {
+        return Err(io::Error::from_raw_os_error(libc::EPERM));
+    }
+    let (parent, name) = at(root, path, follow)?;
+    let name = cstring(name)?;
+    let flags = if directory { AT_REMOVEDIR } else { 0 };
+    match unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(())
+    }
+}
+
+/// Attributes of the file at `path`, relative to `root`, or of the link if it is one; symbolic
+/// links leading to it are only followed if `follow`.
+pub(crate) fn stat(root: &Path, path: &Path, follow: bool) -> io::Result<Attributes> {
+    let (parent, name) = at(root, path, follow)?;
+    Attributes::at(&parent, name)
+}
+
+/// Target of the symbolic link at `path`, relative to `root`.
+pub(crate) fn read_link(root: &Path, path: &Path, follow: bool) -> io::Result<PathBuf> {
+    let (parent, name) = at(root, path, follow)?;
+    let name = cstring(name)?;
+    let mut target = vec![0; libc::PATH_MAX as usize];
+    match unsafe { libc::readlinkat(parent.as_raw_fd(), name.as_ptr(), target.as_mut_ptr() as *mut c_char, target.len()) } {
+        -1 => Err(io::Error::last_os_error()),
+        len => {
+            target.truncate(len as usize);
+            Ok(PathBuf::from(OsString::from_vec(target)))
+        }
+    }
+}
+
+/// Directory stream, closed when dropped.
+struct Dir(*mut libc::DIR);
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        unsafe { libc::closedir(self.0) };
+    }
+}
+
+/// Names in the directory at `path`, relative to `root`, but for `.` and `..`.
+pub(crate) fn read_dir(root: &Path, path: &Path, follow: bool) -> io::Result<Vec<OsString>> {
+    let fd = openat2(&open_root(root)?, path, O_RDONLY | O_DIRECTORY, follow)?.into_raw_fd();
+    let dir = match unsafe { libc::fdopendir(fd) } {
+        dir if dir.is_null() => {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        },
+        dir => Dir(dir)
+    };
+    let mut names = Vec::new();
+    loop {
+        // `readdir` returns null both at the end and on failure, which only the latter sets errno for.
+        unsafe { *libc::__errno_location() = 0 };
+        let entry = unsafe { libc::readdir(dir.0) };
+        if entry.is_null() {
+            return match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(0) => Ok(names),
+                e => Err(e)
+            };
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+        if name != b"." && name != b".." {
+            names.push(OsStr::from_bytes(name).to_os_string());
+        }
+    }
+}
+
+/// Size of the list of extended attribute names of the file at `path`, relative to `root`, or
+/// of the link if it is one.
+pub(crate) fn listxattr_size(root: &Path, path: &Path, follow: bool) -> io::Result<usize> {
+    let (parent, name) = at(root, path, follow)?;
+    // There is no `listxattrat`, and `O_PATH` descriptors do not support `flistxattr`, so the
+    // name is looked up in the directory through procfs.
+    let path = cstring(&Path::new(&format!("/proc/self/fd/{}", parent.as_raw_fd())).join(name))?;
+    match unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        size => Ok(size as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn opens_within_root() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir").join("file"), "inside").unwrap();
+        let mut contents = String::new();
+        open(root.path(), Path::new("dir/file"), O_RDONLY, false).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "inside");
+        assert_eq!(open(root.path(), Path::new("../x"), O_RDONLY, false).unwrap_err().raw_os_error(), Some(libc::EXDEV));
+    }
+
+    #[test]
+    fn refuses_links() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("file"), "outside").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir").join("file"), "inside").unwrap();
+        symlink(outside.path(), root.path().join("escape")).unwrap();
+        symlink("dir", root.path().join("inside")).unwrap();
+
+        assert_eq!(open(root.path(), Path::new("escape/file"), O_RDONLY, false).unwrap_err().raw_os_error(), Some(libc::ELOOP));
+        assert_eq!(open(root.path(), Path::new("escape/file"), O_RDONLY, true).unwrap_err().raw_os_error(), Some(libc::EXDEV));
+        assert_eq!(remove(root.path(), Path::new("escape/file"), false, false).unwrap_err().raw_os_error(), Some(libc::ELOOP));
+        assert_eq!(remove(root.path(), Path::new("escape/file"), false, true).unwrap_err().raw_os_error(), Some(libc::EXDEV));
+        assert!(outside.path().join("file").exists());
+
+        assert_eq!(remove(root.path(), Path::new("inside/file"), false, false).unwrap_err().raw_os_error(), Some(libc::ELOOP));
+        remove(root.path(), Path::new("inside/file"), false, true).unwrap();
+        assert!(!root.path().join("dir").join("file").exists());
+    }
+
+    #[test]
+    fn removes_links_themselves() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        symlink(outside.path(), root.path().join("escape")).unwrap();
+        assert_eq!(remove(root.path(), Path::new("escape"), true, false).unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+        remove(root.path(), Path::new("escape"), false, false).unwrap();
+        assert!(outside.path().exists());
+        assert_eq!(remove(root.path(), Path::new(""), true, false).unwrap_err().raw_os_error(), Some(libc::EPERM));
+    }
+
+    #[test]
+    fn examines_within_root() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir").join("file"), "inside").unwrap();
+        symlink("dir", root.path().join("inside")).unwrap();
+        symlink(outside.path(), root.path().join("escape")).unwrap();
+
+        assert!(stat(root.path(), Path::new(""), false).unwrap().is_dir());
+        assert!(stat(root.path(), Path::new("inside"), false).unwrap().is_symlink());
+        assert_eq!(stat(root.path(), Path::new("inside/file"), false).unwrap_err().raw_os_error(), Some(libc::ELOOP));
+        assert_eq!(stat(root.path(), Path::new("inside/file"), true).unwrap().size, 6);
+        assert_eq!(stat(root.path(), Path::new("escape/file"), true).unwrap_err().raw_os_error(), Some(libc::EXDEV));
+        assert_eq!(read_link(root.path(), Path::new("escape"), false).unwrap(), outside.path());
+        assert_eq!(read_link(root.path(), Path::new("dir"), false).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+
+        let mut names = read_dir(root.path(), Path::new(""), false).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["dir", "escape", "inside"]);
+        assert_eq!(read_dir(root.path(), Path::new("inside"), true).unwrap(), vec!["file"]);
+        assert_eq!(read_dir(root.path(), Path::new("escape"), true).unwrap_err().raw_os_error(), Some(libc::EXDEV));
+        assert_eq!(read_dir(root.path(), Path::new("dir/file"), false).unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+
+        assert!(listxattr_size(root.path(), Path::new("dir/file"), false).is_ok());
+        assert_eq!(listxattr_size(root.path(), Path::new("escape/file"), true).unwrap_err().raw_os_error(), Some(libc::EXDEV));
+    }
+}
@@ -0,0 +1,235 @@
+//! Mount helper, following the mount(8) convention, so that decofs can be mounted from
+//! /etc/fstab and systemd mount units:
+//!
+//! ```text
+//! /mnt/disk1  /decom/disk1  fuse.decofs  allow_other,policy=unlink,ttl=5  0 0
+//! ```
+//!
+//! mount(8) runs `mount.fuse.decofs SOURCE MOUNTPOINT [-sfnv] [-o OPTIONS]`, for which this binary
+//! should be installed (cargo does not allow `.` in binary names), e.g.
+//! `install -m755 target/release/mount_decofs /sbin/mount.fuse.decofs`; or as `mount.decofs`,
+//! for type `decofs`.
+#![warn(missing_docs, bad_style, unused, unused_extern_crates, unused_import_braces, unused_qualifications, missing_debug_implementations, unused_variables)]
+
+use std::path::PathBuf;
+use std::process;
+
+use clap::{App, Arg, ArgMatches};
+
+use rust_decofs::{Config, Service};
+
+/// Options which are applied to the configuration, with `Config::set`.
+const SETTINGS: &[&str] = &["ttl", "attr_ttl", "log", "deny_errno", "policy", "branch", "precedence", "include", "exclude", "inode_store", "threads", "symlinks", "ownership", "squash", "uid_map", "gid_map"];
+
+/// Options meaningful only to mount(8), systemd or fstab, which are not passed to FUSE.
+const IGNORED: &[&str] = &["defaults", "auto", "noauto", "user", "users", "nouser", "owner", "group", "_netdev", "nofail"];
+
+fn app() -> App<'static, 'static> {
+    App::new("mount.decofs")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("mount(8) helper for decofs.\n\nOptions are FUSE mount options, or decofs settings: config=FILE, pidfile=FILE, branch=DIR (repeatable), precedence=first|newest, include=GLOB, exclude=GLOB (repeatable), one_file_system, watch, symlinks=expose|follow|rewrite|hide, ownership=source|squash|map|passwd, squash=UID:GID, uid_map=UID:UID and gid_map=GID:GID (repeatable), inode_store=FILE, threads=N, policy=delete|unlink|readonly, ttl=SECS, attr_ttl=SECS, deny_errno=ERRNO and log=FILTER.")
+        .arg(Arg::with_name("source")
+             .value_name("SOURCE")
+             .help("Directory to expose through the mount")
+             .required(true))
+        .arg(Arg::with_name("mountpoint")
+             .value_name("MOUNTPOINT")
+             .help("Empty directory on which to mount the filesystem")
+             .required(true))
+        .arg(Arg::with_name("option")
+             .short("o")
+             .value_name("OPTION[,OPTION...]")
+             .help("Mount options")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("sloppy")
+             .short("s")
+             .help("Ignore unknown mount options"))
+        .arg(Arg::with_name("fake")
+             .short("f")
+             .help("Validate the arguments, but do not mount"))
+        .arg(Arg::with_name("no-mtab")
+             .short("n")
+             .help("Accepted for compatibility; there is no mtab to update"))
+        .arg(Arg::with_name("verbose")
+             .short("v")
+             .help("Log at info level, unless RUST_LOG or log= is set"))
+        .arg(Arg::with_name("type")
+             .short("t")
+             .value_name("TYPE")
+             .help("Filesystem type, decofs or fuse.decofs")
+             .takes_value(true))
+        .arg(Arg::with_name("namespace")
+             .short("N")
+             .value_name("NAMESPACE")
+             .help("Unsupported")
+             .takes_value(true))
+}
+
+fn from_matches(matches: &ArgMatches) -> Result<(Service, bool), String> {
+    if matches.is_present("namespace") {
+        return Err("mounting in another namespace (-N) is not supported".to_string());
+    }
+    match matches.value_of("type") {
+        None | Some("decofs") | Some("fuse.decofs") => (),
+        Some(other) => return Err(format!("unsupported filesystem type '{}'", other)),
+    };
+    let sloppy = matches.is_present("sloppy");
+    let options = matches.values_of("option").into_iter().flatten()
+        .flat_map(|list| list.split(','))
+        .filter(|option| !option.is_empty())
+        .map(|option| match option.find('=') {
+            Some(i) => (&option[..i], Some(&option[i + 1..])),
+            None => (option, None),
+        })
+        .collect::<Vec<_>>();
+
+    // The config file provides defaults, so is loaded before any other option is applied.
+    let config_path = options.iter()
+        .rfind(|(key, _)| *key == "config")
+        .map(|(_, value)| value.map(PathBuf::from).ok_or("mount option 'config' requires a value"))
+        .transpose()?;
+    let mut config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut mount_options = config.mount_options()?;
+    let mut pidfile = None;
+    for (key, value) in options {
+        let result = match (key, value) {
+            ("config", _) => Ok(()),
+            ("pidfile", Some(value)) => {
+                pidfile = Some(PathBuf::from(value));
+                Ok(())
+            },
+            ("one_file_system", None) => {
+                config.one_file_system = true;
+                Ok(())
+            },
+            ("watch", None) => {
+                config.watch = true;
+                Ok(())
+            },
+            (key, Some(value)) if SETTINGS.contains(&key) => config.set(key, value),
+            (key, None) if key == "pidfile" || SETTINGS.contains(&key) => Err(format!("mount option '{}' requires a value", key)),
+            (key, _) if IGNORED.contains(&key) || key.starts_with("x-") || key == "comment" => Ok(()),
+            (key, Some(value)) => mount_options.set(&format!("{}={}", key, value)),
+            (key, None) => mount_options.set(key),
+        };
+        match result {
+            Err(e) if sloppy => eprintln!("mount.decofs: ignoring: {}", e),
+            result => result?,
+        };
+    }
+    if matches.is_present("verbose") && config.log.is_none() {
+        config.log = Some("info".to_string());
+    }
+
+    let service = Service {
+        mountpoint: PathBuf::from(matches.value_of_os("mountpoint").unwrap()),
+        sources: std::iter::once(PathBuf::from(matches.value_of_os("source").unwrap()))
+            .chain(config.branches.iter().cloned())
+            .collect(),
+        options: mount_options,
+        // mount(8) waits for the helper to exit, so it must detach once mounted.
+        daemon: true,
+        pidfile,
+        config_path,
+        config,
+    };
+    Ok((service.validate()?, matches.is_present("fake")))
+}
+
+fn main() {
+    let (service, fake) = match from_matches(&app().get_matches()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("mount.decofs: {}", e);
+            process::exit(1);
+        }
+    };
+    if fake {
+        return;
+    }
+    service.init_logger();
+    if let Err(e) = service.run() {
+        eprintln!("mount.decofs: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn parse(args: &[&str]) -> Result<(Service, bool), String> {
+        let matches = app().get_matches_from_safe(args).map_err(|e| e.message)?;
+        from_matches(&matches)
+    }
+
+    #[test]
+    fn fstab_options() {
+        let source = tempdir().unwrap();
+        let mountpoint = tempdir().unwrap();
+        let (source, mountpoint) = (source.path().to_str().unwrap(), mountpoint.path().to_str().unwrap());
+        let (service, fake) = parse(&["mount.decofs", source, mountpoint, "-n", "-o",
+                                     "rw,noauto,nofail,x-systemd.automount,allow_other,fsname=disk1,policy=readonly,ttl=5,attr_ttl=60,watch,pidfile=decofs.pid,exclude=/lost+found,one_file_system"]).unwrap();
+        assert!(!fake);
+        assert!(service.daemon);
+        assert!(service.options.contains("fsname"));
+        assert!(!service.options.contains("noauto"));
+        assert!(!service.config.allow.unlink);
+        assert_eq!(service.config.ttl, 5.0);
+        assert_eq!(service.config.attr_ttl, Some(60.0));
+        assert!(service.config.watch);
+        assert_eq!(service.config.exclude, vec!["/lost+found"]);
+        assert!(service.config.one_file_system);
+        assert!(service.pidfile.unwrap().is_absolute());
+    }
+
+    #[test]
+    fn branch_options() {
+        let (source, branch) = (tempdir().unwrap(), tempdir().unwrap());
+        let mountpoint = tempdir().unwrap();
+        let option = format!("branch={},precedence=newest,inode_store=/var/lib/decofs/disk1.inodes,threads=2,symlinks=hide,ownership=passwd,uid_map=0:1000", branch.path().to_str().unwrap());
+        let (service, _) = parse(&["mount.decofs", source.path().to_str().unwrap(), mountpoint.path().to_str().unwrap(), "-o", &option]).unwrap();
+        assert_eq!(service.sources, vec![fs::canonicalize(source.path()).unwrap(), fs::canonicalize(branch.path()).unwrap()]);
+        assert_eq!(service.config.precedence, rust_decofs::Precedence::Newest);
+        assert_eq!(service.config.inode_store, Some(PathBuf::from("/var/lib/decofs/disk1.inodes")));
+        assert_eq!(service.config.threads, 2);
+        assert_eq!(service.config.symlinks, rust_decofs::Symlinks::Hide);
+        assert_eq!(service.config.ownership, "passwd");
+        assert_eq!(service.config.uid_map, vec!["0:1000"]);
+    }
+
+    #[test]
+    fn config_option() {
+        let source = tempdir().unwrap();
+        let mountpoint = tempdir().unwrap();
+        let config = source.path().join("decofs.toml");
+        fs::write(&config, "options = [\"ro\"]\nttl = 2.0\n[allow]\nrmdir = false\n").unwrap();
+        let (source, mountpoint) = (source.path().to_str().unwrap(), mountpoint.path().to_str().unwrap());
+        let option = format!("ttl=3,config={}", config.to_str().unwrap());
+        let (service, _) = parse(&["mount.decofs", source, mountpoint, "-o", &option]).unwrap();
+        assert!(service.options.contains("ro"));
+        assert!(!service.config.allow.rmdir);
+        assert_eq!(service.config.ttl, 3.0);
+        assert_eq!(service.config_path, Some(fs::canonicalize(&config).unwrap()));
+    }
+
+    #[test]
+    fn unknown_options() {
+        let source = tempdir().unwrap();
+        let mountpoint = tempdir().unwrap();
+        let (source, mountpoint) = (source.path().to_str().unwrap(), mountpoint.path().to_str().unwrap());
+        assert!(parse(&["mount.decofs", source, mountpoint, "-o", "bogus"]).is_err());
+        assert!(parse(&["mount.decofs", source, mountpoint, "-o", "policy=write"]).is_err());
+        assert!(parse(&["mount.decofs", source, mountpoint, "-s", "-o", "bogus,policy=write"]).is_ok());
+        assert!(parse(&["mount.decofs", source, mountpoint, "-N", "1"]).is_err());
+        assert!(parse(&["mount.decofs", source, mountpoint, "-t", "ext4"]).is_err());
+        assert!(parse(&["mount.decofs", source, mountpoint, "-f", "-t", "fuse.decofs"]).unwrap().1);
+    }
+}
@@ -0,0 +1,165 @@
+//! Construction of `DecoFS` instances.
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use time::Timespec;
+
+use crate::config::Settings;
+use crate::filesystem::DecoFS;
+use crate::filter::Filter;
+use crate::hook::Hook;
+use crate::ownership::Ownership;
+use crate::policy::{OperationPolicy, SettingsPolicy};
+use crate::symlinks::Symlinks;
+use crate::union::Precedence;
+use crate::workers;
+
+/// Builder for `DecoFS`.
+///
+/// ```no_run
+/// use rust_decofs::{DecoFsBuilder, DefaultPolicy, MountOptions};
+/// use std::time::Duration;
+///
+/// let fs = DecoFsBuilder::new("/mnt/disk1")
+///     .ttl(Duration::from_secs(5))
+///     .policy(DefaultPolicy)
+///     .build();
+/// fs.mount("/decom/disk1", &MountOptions::default())?.run()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct DecoFsBuilder {
+    sourceroots: Vec<PathBuf>,
+    precedence: Precedence,
+    settings: Arc<RwLock<Settings>>,
+    policy: Option<Box<dyn OperationPolicy>>,
+    hooks: Vec<Box<dyn Hook>>,
+    threads: usize,
+    inode_store: Option<PathBuf>,
+    watch: bool,
+}
+
+fn timespec(duration: Duration) -> Timespec {
+    Timespec { sec: duration.as_secs() as i64, nsec: duration.subsec_nanos() as i32 }
+}
+
+impl DecoFsBuilder {
+    /// Start building a filesystem exposing the directory `sourceroot`.
+    pub fn new<P: AsRef<Path>>(sourceroot: P) -> DecoFsBuilder {
+        DecoFsBuilder {
+            sourceroots: vec![sourceroot.as_ref().to_path_buf()],
+            precedence: Precedence::default(),
+            settings: Arc::new(RwLock::new(Settings::default())),
+            policy: None,
+            hooks: Vec::new(),
+            threads: workers::DEFAULT_THREADS,
+            inode_store: None,
+            watch: false,
+        }
+    }
+
+    /// Add a further directory to the union presented at the root of the mount, after those
+    /// already given.
+    pub fn branch<P: AsRef<Path>>(mut self, sourceroot: P) -> DecoFsBuilder {
+        self.sourceroots.push(sourceroot.as_ref().to_path_buf());
+        self
+    }
+
+    /// Which branch's entry is presented when a name exists in several; defaults to the first.
+    pub fn precedence(mut self, precedence: Precedence) -> DecoFsBuilder {
+        self.precedence = precedence;
+        self
+    }
+
+    /// Use shared `settings`, which may be changed while mounted (e.g. on reloading configuration).
+    pub fn settings(mut self, settings: Arc<RwLock<Settings>>) -> DecoFsBuilder {
+        self.settings = settings;
+        self
+    }
+
+    /// Time for which the kernel may cache attributes and entries; applied to the current settings.
+    pub fn ttl(self, ttl: Duration) -> DecoFsBuilder {
+        {
+            let mut settings = self.settings.write().unwrap();
+            settings.ttl = timespec(ttl);
+            settings.attr_ttl = timespec(ttl);
+        }
+        self
+    }
+
+    /// Time for which the kernel may cache attributes fetched on their own, in place of that given
+    /// to `ttl`; applied to the current settings.
+    pub fn attr_ttl(self, ttl: Duration) -> DecoFsBuilder {
+        self.settings.write().unwrap().attr_ttl = timespec(ttl);
+        self
+    }
+
+    /// Paths exposed through the mount; applied to the current settings.
+    pub fn filter(self, filter: Filter) -> DecoFsBuilder {
+        self.settings.write().unwrap().filter = filter;
+        self
+    }
+
+    /// Whether to hide anything on another filesystem from its parent directory, such as nested
+    /// mounts; applied to the current settings.
+    pub fn one_file_system(self, one_file_system: bool) -> DecoFsBuilder {
+        self.settings.write().unwrap().one_file_system = one_file_system;
+        self
+    }
+
+    /// How symbolic links on the source are presented; applied to the current settings.
+    pub fn symlinks(self, symlinks: Symlinks) -> DecoFsBuilder {
+        self.settings.write().unwrap().symlinks = symlinks;
+        self
+    }
+
+    /// How the owner and group of source files are presented; applied to the current settings.
+    pub fn ownership(self, ownership: Ownership) -> DecoFsBuilder {
+        self.settings.write().unwrap().ownership = ownership;
+        self
+    }
+
+    /// Number of threads serving requests, so that a slow one (e.g. reading from a failing disk)
+    /// does not hold up others; with none, each request is served in turn as it arrives.
+    pub fn threads(mut self, threads: usize) -> DecoFsBuilder {
+        self.threads = threads;
+        self
+    }
+
+    /// File recording the inode number allocated to each source file, so that they are unchanged
+    /// when the filesystem is next mounted; created if missing, and opened on mounting.
+    pub fn inode_store<P: AsRef<Path>>(mut self, path: P) -> DecoFsBuilder {
+        self.inode_store = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Whether to watch the sources, once mounted, for names created, removed or modified other
+    /// than through the mount, so that they are not presented as they were before.
+    pub fn watch(mut self, watch: bool) -> DecoFsBuilder {
+        self.watch = watch;
+        self
+    }
+
+    /// Policy deciding which operations are permitted.
+    ///
+    /// Defaults to a `SettingsPolicy` following the builder's settings.
+    pub fn policy<P: OperationPolicy + 'static>(mut self, policy: P) -> DecoFsBuilder {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Add a hook notified of changes made to the source tree.
+    pub fn hook<H: Hook + 'static>(mut self, hook: H) -> DecoFsBuilder {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Build the filesystem.
+    pub fn build(self) -> DecoFS {
+        let settings = self.settings;
+        settings.write().unwrap().read_accounts(&self.sourceroots);
+        let policy = self.policy.unwrap_or_else(|| Box::new(SettingsPolicy::new(settings.clone())));
+        DecoFS::new(self.sourceroots, self.precedence, settings, policy, self.hooks, self.threads, self.inode_store).watching(self.watch)
+    }
+}
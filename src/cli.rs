@@ -0,0 +1,329 @@
+//! Command-line handling for the decofs binary.
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches};
+
+use rust_decofs::{Config, Service};
+
+fn app() -> App<'static, 'static> {
+    App::new("decofs")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Pass-through filesystem to assist with disk decommissioning.\n\nExposes SOURCE at MOUNTPOINT (merging several SOURCEs, if given), allowing files to be read and deleted, but not created, written or renamed.")
+        .arg(Arg::with_name("mountpoint")
+             .value_name("MOUNTPOINT")
+             .help("Empty directory on which to mount the filesystem")
+             .required_unless("config"))
+        .arg(Arg::with_name("source")
+             .value_name("SOURCE")
+             .help("Directory to expose through the mount; further directories are merged in, in order of precedence")
+             .required_unless("config")
+             .multiple(true))
+        .arg(Arg::with_name("precedence")
+             .long("precedence")
+             .value_name("first|newest")
+             .help("Which SOURCE's entry to present when a name exists in several: the first given (default), or the most recently modified")
+             .takes_value(true)
+             .possible_values(&["first", "newest"]))
+        .arg(Arg::with_name("symlinks")
+             .long("symlinks")
+             .value_name("expose|follow|rewrite|hide")
+             .help("How to present symbolic links on SOURCE: as they are (default), as what they lead to if within SOURCE, with targets rewritten to stay within the mount, or not at all")
+             .takes_value(true)
+             .possible_values(&["expose", "follow", "rewrite", "hide"]))
+        .arg(Arg::with_name("ownership")
+             .long("ownership")
+             .value_name("source|squash|map|passwd")
+             .help("How to present file owners: as on SOURCE (default), all as --squash, mapped by --uid-map and --gid-map, or by name from SOURCE's /etc/passwd and /etc/group to the host's")
+             .takes_value(true)
+             .possible_values(&["source", "squash", "map", "passwd"]))
+        .arg(Arg::with_name("squash")
+             .long("squash")
+             .value_name("UID:GID")
+             .help("Owner and group of every file with --ownership squash [default: 65534:65534]")
+             .takes_value(true))
+        .arg(Arg::with_name("uid_map")
+             .long("uid-map")
+             .value_name("SOURCE:PRESENTED")
+             .help("Present files owned by uid SOURCE as owned by PRESENTED, with --ownership map or passwd (may be repeated)")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("gid_map")
+             .long("gid-map")
+             .value_name("SOURCE:PRESENTED")
+             .help("Present files of gid SOURCE as of PRESENTED, with --ownership map or passwd (may be repeated)")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("include")
+             .long("include")
+             .value_name("GLOB")
+             .help("Only expose paths matching GLOB, relative to the root of the mount, e.g. /home/** (may be repeated)")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("exclude")
+             .long("exclude")
+             .value_name("GLOB")
+             .help("Hide paths matching GLOB, e.g. /lost+found or **/.Trash-* (may be repeated)")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("one-file-system")
+             .short("x")
+             .long("one-file-system")
+             .help("Hide anything on another filesystem from its parent directory, such as nested mounts"))
+        .arg(Arg::with_name("inode-store")
+             .long("inode-store")
+             .value_name("FILE")
+             .help("Record inode numbers in FILE, so they are unchanged after a restart")
+             .takes_value(true))
+        .arg(Arg::with_name("threads")
+             .long("threads")
+             .value_name("N")
+             .help("Serve requests on N threads, so a slow disk does not hold up others [default: 8]")
+             .takes_value(true))
+        .arg(Arg::with_name("ttl")
+             .long("ttl")
+             .value_name("SECS")
+             .help("Time for which the kernel may cache names and attributes [default: 1]")
+             .takes_value(true))
+        .arg(Arg::with_name("attr_ttl")
+             .long("attr-ttl")
+             .value_name("SECS")
+             .help("Time for which the kernel may cache attributes fetched on their own [default: --ttl]")
+             .takes_value(true))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("Watch SOURCE for changes made other than through the mount"))
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("TOML configuration file; MOUNTPOINT, SOURCE and -o take precedence over its settings")
+             .takes_value(true))
+        .arg(Arg::with_name("option")
+             .short("o")
+             .value_name("OPTION[,OPTION...]")
+             .help("FUSE mount option, e.g. ro, fsname=disk1, subtype=decofs (may be repeated)")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("no-allow-other")
+             .long("no-allow-other")
+             .help("Only allow the mounting user to access the mount"))
+        .arg(Arg::with_name("daemon")
+             .short("d")
+             .long("daemon")
+             .help("Run in the background once mounted"))
+        .arg(Arg::with_name("pidfile")
+             .long("pidfile")
+             .value_name("FILE")
+             .help("Write the process id to FILE, removing it on exit")
+             .takes_value(true))
+}
+
+/// Parse the process arguments, printing usage and exiting if they are malformed.
+pub fn parse() -> Result<Service, String> {
+    from_matches(&app().get_matches())
+}
+
+fn from_matches(matches: &ArgMatches) -> Result<Service, String> {
+    let config_path = matches.value_of_os("config").map(PathBuf::from);
+    let mut config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mountpoint = matches.value_of_os("mountpoint").map(PathBuf::from)
+        .or_else(|| config.mountpoint.clone())
+        .ok_or("no mountpoint given on the command line or in the config")?;
+    let sources = match matches.values_of_os("source") {
+        Some(sources) => sources.map(PathBuf::from).collect(),
+        None => config.source.iter().chain(&config.branches).cloned().collect::<Vec<_>>(),
+    };
+    if sources.is_empty() {
+        return Err("no source given on the command line or in the config".to_string());
+    }
+    if let Some(precedence) = matches.value_of("precedence") {
+        config.set("precedence", precedence)?;
+    }
+    if let Some(symlinks) = matches.value_of("symlinks") {
+        config.set("symlinks", symlinks)?;
+    }
+    if let Some(squash) = matches.value_of("squash") {
+        config.set("squash", squash)?;
+    }
+    for key in &["uid_map", "gid_map"] {
+        for pair in matches.values_of(key).into_iter().flatten() {
+            config.set(key, pair)?;
+        }
+    }
+    if let Some(ownership) = matches.value_of("ownership") {
+        config.set("ownership", ownership)?;
+    }
+    if let Some(path) = matches.value_of_os("inode-store") {
+        config.inode_store = Some(PathBuf::from(path));
+    }
+    if let Some(threads) = matches.value_of("threads") {
+        config.set("threads", threads)?;
+    }
+    if matches.is_present("one-file-system") {
+        config.one_file_system = true;
+    }
+    for key in &["ttl", "attr_ttl"] {
+        if let Some(ttl) = matches.value_of(key) {
+            config.set(key, ttl)?;
+        }
+    }
+    if matches.is_present("watch") {
+        config.watch = true;
+    }
+    for key in &["include", "exclude"] {
+        for glob in matches.values_of(key).into_iter().flatten() {
+            config.set(key, glob)?;
+        }
+    }
+    let mut options = config.mount_options()?;
+    if matches.is_present("no-allow-other") {
+        options.remove("allow_other");
+    }
+    for list in matches.values_of("option").into_iter().flatten() {
+        options.set_all(list)?;
+    }
+    let daemon = matches.is_present("daemon");
+    let pidfile = matches.value_of_os("pidfile").map(PathBuf::from);
+    Service { mountpoint, sources, options, daemon, pidfile, config_path, config }.validate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn parse(args: &[&str]) -> Result<Service, String> {
+        let matches = app().get_matches_from_safe(args).map_err(|e| e.message)?;
+        from_matches(&matches)
+    }
+
+    #[test]
+    fn missing_arguments() {
+        assert!(parse(&["decofs"]).is_err());
+        assert!(parse(&["decofs", "mnt"]).is_err());
+    }
+
+    #[test]
+    fn valid_arguments() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let args = parse(&["decofs", mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap()]).unwrap();
+        assert_eq!(args.mountpoint, fs::canonicalize(mountpoint.path()).unwrap());
+        assert_eq!(args.sources, vec![fs::canonicalize(source.path()).unwrap()]);
+        assert!(!args.daemon);
+        assert_eq!(args.pidfile, None);
+    }
+
+    #[test]
+    fn mount_options() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let (mountpoint, source) = (mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap());
+        let args = parse(&["decofs", "-o", "ro,fsname=disk1", "-o", "subtype=decofs", "--no-allow-other", mountpoint, source]).unwrap();
+        assert!(args.options.contains("ro"));
+        assert!(args.options.contains("subtype"));
+        assert!(!args.options.contains("allow_other"));
+        assert!(parse(&["decofs", "-o", "bogus", mountpoint, source]).is_err());
+    }
+
+    #[test]
+    fn config_file() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let config = source.path().join("decofs.toml");
+        fs::write(&config, format!("source = {:?}\nmountpoint = {:?}\noptions = [\"ro\"]\n[allow]\nrmdir = false\n",
+                                   source.path(), mountpoint.path())).unwrap();
+        let args = parse(&["decofs", "--config", config.to_str().unwrap()]).unwrap();
+        assert_eq!(args.sources, vec![fs::canonicalize(source.path()).unwrap()]);
+        assert!(args.options.contains("ro"));
+        assert!(!args.config.allow.rmdir);
+
+        let args = parse(&["decofs", "--config", config.to_str().unwrap(), "-o", "rw"]).unwrap();
+        assert!(!args.options.contains("ro"));
+    }
+
+    #[test]
+    fn multiple_sources() {
+        let mountpoint = tempdir().unwrap();
+        let (first, second) = (tempdir().unwrap(), tempdir().unwrap());
+        let args = parse(&["decofs", "--precedence", "newest", mountpoint.path().to_str().unwrap(),
+                           first.path().to_str().unwrap(), second.path().to_str().unwrap()]).unwrap();
+        assert_eq!(args.sources, vec![fs::canonicalize(first.path()).unwrap(), fs::canonicalize(second.path()).unwrap()]);
+        assert_eq!(args.config.precedence, rust_decofs::Precedence::Newest);
+        assert!(parse(&["decofs", "--precedence", "last", mountpoint.path().to_str().unwrap(), first.path().to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn filters() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let (mountpoint, source) = (mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap());
+        let args = parse(&["decofs", "--include", "/home/**", "--exclude", "**/.cache", "-x", mountpoint, source]).unwrap();
+        assert!(args.config.one_file_system);
+        assert_eq!(args.config.include, vec!["/home/**"]);
+        assert_eq!(args.config.exclude, vec!["**/.cache"]);
+        assert!(parse(&["decofs", "--exclude", "[a", mountpoint, source]).is_err());
+    }
+
+    #[test]
+    fn ttls() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let (mountpoint, source) = (mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap());
+        let args = parse(&["decofs", "--ttl", "5", "--attr-ttl", "60", "--watch", mountpoint, source]).unwrap();
+        assert_eq!((args.config.ttl, args.config.attr_ttl), (5.0, Some(60.0)));
+        assert!(args.config.watch);
+        assert!(!parse(&["decofs", mountpoint, source]).unwrap().config.watch);
+        assert!(parse(&["decofs", "--attr-ttl", "-1", mountpoint, source]).is_err());
+    }
+
+    #[test]
+    fn inode_store() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let (mountpoint, source) = (mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap());
+        let args = parse(&["decofs", "--inode-store", "disk1.inodes", mountpoint, source]).unwrap();
+        assert_eq!(args.config.inode_store, Some(std::env::current_dir().unwrap().join("disk1.inodes")));
+    }
+
+    #[test]
+    fn threads() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let (mountpoint, source) = (mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap());
+        assert_eq!(parse(&["decofs", mountpoint, source]).unwrap().config.threads, 8);
+        assert_eq!(parse(&["decofs", "--threads", "2", mountpoint, source]).unwrap().config.threads, 2);
+        assert!(parse(&["decofs", "--threads", "many", mountpoint, source]).is_err());
+    }
+
+    #[test]
+    fn symlinks() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let (mountpoint, source) = (mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap());
+        let args = parse(&["decofs", "--symlinks", "rewrite", mountpoint, source]).unwrap();
+        assert_eq!(args.config.symlinks, rust_decofs::Symlinks::Rewrite);
+        assert!(parse(&["decofs", "--symlinks", "ignore", mountpoint, source]).is_err());
+    }
+
+    #[test]
+    fn ownership() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let (mountpoint, source) = (mountpoint.path().to_str().unwrap(), source.path().to_str().unwrap());
+        let args = parse(&["decofs", "--ownership", "squash", "--squash", "1000:100", mountpoint, source]).unwrap();
+        assert_eq!(args.config.settings().ownership, rust_decofs::Ownership::Squash(1000, 100));
+        let args = parse(&["decofs", "--ownership", "map", "--uid-map", "0:1000", "--uid-map", "1:1001", mountpoint, source]).unwrap();
+        assert_eq!(args.config.uid_map, vec!["0:1000", "1:1001"]);
+        assert!(parse(&["decofs", "--gid-map", "100", mountpoint, source]).is_err());
+    }
+}
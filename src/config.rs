@@ -0,0 +1,440 @@
+//! TOML configuration file, describing a mount and the behaviour of the filesystem.
+//!
+//! ```toml
+//! source = "/mnt/disk1"
+//! branches = ["/mnt/disk2"]
+//! precedence = "first"
+//! mountpoint = "/decom/disk1"
+//! options = ["ro", "fsname=disk1"]
+//! ttl = 1.0
+//! attr_ttl = 60.0
+//! watch = true
+//! log = "info"
+//! deny_errno = "EROFS"
+//! exclude = ["/lost+found"]
+//! one_file_system = false
+//! symlinks = "rewrite"
+//! ownership = "map"
+//! uid_map = ["1000:2000"]
+//! inode_store = "/var/lib/decofs/disk1.inodes"
+//! threads = 8
+//!
+//! [allow]
+//! unlink = true
+//! rmdir = false
+//! ```
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use libc::{c_int, EACCES, EPERM, EROFS};
+use serde::Deserialize;
+use time::Timespec;
+
+use crate::filter::Filter;
+use crate::options::MountOptions;
+use crate::ownership::{self, IdMap, Ownership};
+use crate::symlinks::Symlinks;
+use crate::union::Precedence;
+use crate::workers::DEFAULT_THREADS;
+
+/// Contents of a configuration file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory exposed through the mount.
+    pub source: Option<PathBuf>,
+    /// Further directories merged with `source`, in order of precedence.
+    pub branches: Vec<PathBuf>,
+    /// Which branch's entry is presented when a name exists in several: "first" or "newest".
+    pub precedence: Precedence,
+    /// Directory on which the filesystem is mounted.
+    pub mountpoint: Option<PathBuf>,
+    /// FUSE mount options, applied on top of the defaults.
+    pub options: Vec<String>,
+    /// Time, in seconds, for which the kernel may cache entries, and the attributes looked up
+    /// with them.
+    pub ttl: f64,
+    /// Time, in seconds, for which the kernel may cache attributes fetched on their own; `ttl`
+    /// if unset.
+    pub attr_ttl: Option<f64>,
+    /// Watch the sources for changes made other than through the mount.
+    pub watch: bool,
+    /// Log filter, in `RUST_LOG` syntax; `RUST_LOG` takes precedence when set.
+    pub log: Option<String>,
+    /// Operations permitted through the mount.
+    pub allow: Allow,
+    /// Error returned for denied operations: one of "EPERM", "EROFS" or "EACCES".
+    pub deny_errno: String,
+    /// Globs for the only paths to expose; all are exposed if empty.
+    pub include: Vec<String>,
+    /// Globs for paths which are hidden, and so cannot be deleted.
+    pub exclude: Vec<String>,
+    /// Hide anything on another filesystem from its parent directory, such as nested mounts.
+    pub one_file_system: bool,
+    /// How symbolic links are presented: "expose", "follow", "rewrite" or "hide".
+    pub symlinks: Symlinks,
+    /// How file ownership is presented: "source", "squash", "map" or "passwd".
+    pub ownership: String,
+    /// Owner and group of every file when `ownership` is "squash", as "UID:GID".
+    pub squash: String,
+    /// Users presented in place of those on the source, as "SOURCE:PRESENTED" uids, when
+    /// `ownership` is "map" or "passwd".
+    pub uid_map: Vec<String>,
+    /// Groups presented in place of those on the source, as "SOURCE:PRESENTED" gids.
+    pub gid_map: Vec<String>,
+    /// File recording the inode numbers allocated, so that they persist across restarts.
+    pub inode_store: Option<PathBuf>,
+    /// Number of threads serving requests; with none, each is served in turn as it arrives.
+    pub threads: usize,
+}
+
+/// Modifying operations which may be permitted through the mount.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Allow {
+    /// Deletion of files.
+    pub unlink: bool,
+    /// Deletion of empty directories.
+    pub rmdir: bool,
+}
+
+/// Behaviour of a mounted filesystem which may be changed without remounting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Time for which the kernel may cache entries, and the attributes looked up with them.
+    pub ttl: Timespec,
+    /// Time for which the kernel may cache attributes fetched on their own.
+    pub attr_ttl: Timespec,
+    /// Operations permitted through the mount.
+    pub allow: Allow,
+    /// Error returned for denied operations.
+    pub deny_errno: c_int,
+    /// Paths exposed through the mount.
+    pub filter: Filter,
+    /// Whether paths on another filesystem from their parent directory are hidden.
+    pub one_file_system: bool,
+    /// How symbolic links are presented.
+    pub symlinks: Symlinks,
+    /// How file ownership is presented.
+    pub ownership: Ownership,
+    /// Users and groups of the sources, mapped by name to those of the host, for
+    /// `Ownership::Passwd`; read from the sources on building the filesystem, and on reloading.
+    pub accounts: IdMap,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            source: None,
+            branches: Vec::new(),
+            precedence: Precedence::default(),
+            mountpoint: None,
+            options: Vec::new(),
+            ttl: 1.0,
+            attr_ttl: None,
+            watch: false,
+            log: None,
+            allow: Allow::default(),
+            deny_errno: "EPERM".to_string(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            one_file_system: false,
+            symlinks: Symlinks::default(),
+            ownership: "source".to_string(),
+            squash: "65534:65534".to_string(),
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            inode_store: None,
+            threads: DEFAULT_THREADS,
+        }
+    }
+}
+
+impl Default for Allow {
+    fn default() -> Allow {
+        Allow { unlink: true, rmdir: true }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Config::default().settings()
+    }
+}
+
+impl Settings {
+    /// Read the accounts of `sources`, if ownership is presented by them; there are none otherwise.
+    pub(crate) fn read_accounts(&mut self, sources: &[PathBuf]) {
+        self.accounts = match self.ownership {
+            Ownership::Passwd(_) => ownership::accounts(sources, Path::new("/")),
+            _ => IdMap::default()
+        };
+    }
+}
+
+impl Config {
+    /// Read and validate a configuration file.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("config {:?}: {}", path, e))?;
+        Config::parse(&contents)
+            .map_err(|e| format!("config {:?}: {}", path, e))
+    }
+
+    fn parse(contents: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for (key, ttl) in [("ttl", Some(config.ttl)), ("attr_ttl", config.attr_ttl)] {
+            match ttl {
+                Some(ttl) if !ttl.is_finite() || ttl < 0.0 => return Err(format!("invalid {} {}", key, ttl)),
+                _ => ()
+            }
+        }
+        config.mount_options()?;
+        errno(&config.deny_errno)?;
+        Filter::new(&config.include, &config.exclude)?;
+        config.ownership()?;
+        Ok(config)
+    }
+
+    /// Mount options, with those from the configuration applied on top of the defaults.
+    pub fn mount_options(&self) -> Result<MountOptions, String> {
+        let mut options = MountOptions::default();
+        for option in &self.options {
+            options.set_all(option)?;
+        }
+        Ok(options)
+    }
+
+    /// Apply a setting given as a `key=value` mount option: one of `ttl`, `attr_ttl`, `log`,
+    /// `deny_errno`,
+    /// `branch`, `include` or `exclude` (added to the list), `precedence`, `symlinks`,
+    /// `ownership`, `squash`, `uid_map` or `gid_map` (added to the list), `inode_store`, `threads`,
+    /// or `policy`, which is `delete` (allow unlink and rmdir), `unlink` or `readonly`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "branch" => self.branches.push(PathBuf::from(value)),
+            "include" | "exclude" => {
+                Filter::new(&[value], &[])?;
+                match key {
+                    "include" => self.include.push(value.to_string()),
+                    _ => self.exclude.push(value.to_string()),
+                }
+            },
+            "precedence" => self.precedence = match value {
+                "first" => Precedence::First,
+                "newest" => Precedence::Newest,
+                _ => return Err(format!("unknown precedence '{}', expected first or newest", value)),
+            },
+            "symlinks" => self.symlinks = match value {
+                "expose" => Symlinks::Expose,
+                "follow" => Symlinks::Follow,
+                "rewrite" => Symlinks::Rewrite,
+                "hide" => Symlinks::Hide,
+                _ => return Err(format!("unknown symlinks '{}', expected expose, follow, rewrite or hide", value)),
+            },
+            "ownership" => {
+                ownership(value, &self.squash, &self.uid_map, &self.gid_map)?;
+                self.ownership = value.to_string();
+            },
+            "squash" => {
+                ownership::pair(value)?;
+                self.squash = value.to_string();
+            },
+            "uid_map" | "gid_map" => {
+                ownership::pair(value)?;
+                match key {
+                    "uid_map" => self.uid_map.push(value.to_string()),
+                    _ => self.gid_map.push(value.to_string()),
+                }
+            },
+            "ttl" | "attr_ttl" => {
+                let ttl = match value.parse::<f64>() {
+                    Ok(ttl) if ttl.is_finite() && ttl >= 0.0 => ttl,
+                    _ => return Err(format!("invalid {} '{}'", key, value)),
+                };
+                match key {
+                    "ttl" => self.ttl = ttl,
+                    _ => self.attr_ttl = Some(ttl),
+                }
+            },
+            "log" => self.log = Some(value.to_string()),
+            "inode_store" => self.inode_store = Some(PathBuf::from(value)),
+            "threads" => self.threads = value.parse().map_err(|_| format!("invalid threads '{}'", value))?,
+            "deny_errno" => {
+                errno(value)?;
+                self.deny_errno = value.to_string();
+            },
+            "policy" => self.allow = match value {
+                "delete" => Allow { unlink: true, rmdir: true },
+                "unlink" => Allow { unlink: true, rmdir: false },
+                "readonly" => Allow { unlink: false, rmdir: false },
+                _ => return Err(format!("unknown policy '{}', expected delete, unlink or readonly", value)),
+            },
+            _ => return Err(format!("unknown setting '{}'", key)),
+        };
+        Ok(())
+    }
+
+    /// Settings which apply to the mounted filesystem.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            ttl: timespec(self.ttl),
+            attr_ttl: timespec(self.attr_ttl.unwrap_or(self.ttl)),
+            allow: self.allow.clone(),
+            deny_errno: errno(&self.deny_errno).unwrap_or(EPERM),
+            filter: Filter::new(&self.include, &self.exclude).unwrap_or_default(),
+            one_file_system: self.one_file_system,
+            symlinks: self.symlinks,
+            ownership: self.ownership().unwrap_or_default(),
+            accounts: IdMap::default(),
+        }
+    }
+
+    /// How file ownership is presented, from `ownership` and the settings for its mode.
+    pub fn ownership(&self) -> Result<Ownership, String> {
+        ownership(&self.ownership, &self.squash, &self.uid_map, &self.gid_map)
+    }
+}
+
+fn ownership(mode: &str, squash: &str, uid_map: &[String], gid_map: &[String]) -> Result<Ownership, String> {
+    let table = |pairs: &[String]| pairs.iter().map(|value| ownership::pair(value)).collect::<Result<_, _>>();
+    let map = || -> Result<IdMap, String> { Ok(IdMap { users: table(uid_map)?, groups: table(gid_map)? }) };
+    match mode {
+        "source" => Ok(Ownership::Source),
+        "squash" => ownership::pair(squash).map(|(uid, gid)| Ownership::Squash(uid, gid)),
+        "map" => Ok(Ownership::Map(map()?)),
+        "passwd" => Ok(Ownership::Passwd(map()?)),
+        _ => Err(format!("unknown ownership '{}', expected source, squash, map or passwd", mode)),
+    }
+}
+
+fn timespec(seconds: f64) -> Timespec {
+    Timespec { sec: seconds.trunc() as i64, nsec: (seconds.fract() * 1e9) as i32 }
+}
+
+fn errno(name: &str) -> Result<c_int, String> {
+    match name {
+        "EPERM" => Ok(EPERM),
+        "EROFS" => Ok(EROFS),
+        "EACCES" => Ok(EACCES),
+        _ => Err(format!("unsupported deny_errno '{}', expected EPERM, EROFS or EACCES", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.settings().ttl, Timespec { sec: 1, nsec: 0 });
+        assert_eq!(config.settings().attr_ttl, Timespec { sec: 1, nsec: 0 });
+    }
+
+    #[test]
+    fn full_config() {
+        let config = Config::parse(r#"
+            source = "/mnt/disk1"
+            branches = ["/mnt/disk2", "/mnt/disk3"]
+            precedence = "newest"
+            mountpoint = "/decom/disk1"
+            options = ["ro", "fsname=disk1"]
+            ttl = 2.5
+            attr_ttl = 60.0
+            watch = true
+            log = "info"
+            deny_errno = "EROFS"
+            one_file_system = true
+            symlinks = "follow"
+            ownership = "map"
+            uid_map = ["1000:2000"]
+            gid_map = ["100:200", "101:201"]
+            inode_store = "/var/lib/decofs/disk1.inodes"
+            threads = 2
+
+            [allow]
+            rmdir = false
+        "#).unwrap();
+        assert_eq!(config.source, Some(PathBuf::from("/mnt/disk1")));
+        assert_eq!(config.branches, vec![PathBuf::from("/mnt/disk2"), PathBuf::from("/mnt/disk3")]);
+        assert_eq!(config.precedence, Precedence::Newest);
+        assert_eq!(config.mountpoint, Some(PathBuf::from("/decom/disk1")));
+        assert!(config.mount_options().unwrap().contains("ro"));
+        assert_eq!(config.log, Some("info".to_string()));
+        let settings = config.settings();
+        assert_eq!(settings.ttl, Timespec { sec: 2, nsec: 500_000_000 });
+        assert_eq!(settings.attr_ttl, Timespec { sec: 60, nsec: 0 });
+        assert!(config.watch);
+        assert_eq!(settings.allow, Allow { unlink: true, rmdir: false });
+        assert_eq!(settings.deny_errno, EROFS);
+        assert!(settings.one_file_system);
+        assert_eq!(settings.symlinks, Symlinks::Follow);
+        assert_eq!(settings.ownership, Ownership::Map(IdMap {
+            users: vec![(1000, 2000)].into_iter().collect(),
+            groups: vec![(100, 200), (101, 201)].into_iter().collect(),
+        }));
+        assert_eq!(config.inode_store, Some(PathBuf::from("/var/lib/decofs/disk1.inodes")));
+        assert_eq!(config.threads, 2);
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        assert!(Config::parse("bogus = 1").is_err());
+        assert!(Config::parse("ttl = -1.0").is_err());
+        assert!(Config::parse("attr_ttl = -1.0").is_err());
+        assert!(Config::parse("options = [\"bogus\"]").is_err());
+        assert!(Config::parse("[allow]\nwrite = true").is_err());
+        assert!(Config::parse("deny_errno = \"ENOENT\"").is_err());
+        assert!(Config::parse("precedence = \"last\"").is_err());
+        assert!(Config::parse("exclude = [\"[a\"]").is_err());
+        assert!(Config::parse("ownership = \"nobody\"").is_err());
+        assert!(Config::parse("uid_map = [\"1000\"]\nownership = \"map\"").is_err());
+    }
+
+    #[test]
+    fn set() {
+        let mut config = Config::default();
+        config.set("ttl", "0.5").unwrap();
+        config.set("policy", "unlink").unwrap();
+        config.set("deny_errno", "EROFS").unwrap();
+        config.set("branch", "/mnt/disk2").unwrap();
+        config.set("precedence", "newest").unwrap();
+        config.set("inode_store", "disk2.inodes").unwrap();
+        assert_eq!(config.inode_store, Some(PathBuf::from("disk2.inodes")));
+        config.set("symlinks", "hide").unwrap();
+        assert_eq!(config.settings().symlinks, Symlinks::Hide);
+        assert!(config.set("symlinks", "ignore").is_err());
+        assert_eq!(config.settings().ownership, Ownership::Source);
+        config.set("ownership", "squash").unwrap();
+        assert_eq!(config.settings().ownership, Ownership::Squash(65534, 65534));
+        config.set("squash", "1000:100").unwrap();
+        assert_eq!(config.settings().ownership, Ownership::Squash(1000, 100));
+        config.set("ownership", "passwd").unwrap();
+        config.set("uid_map", "0:1000").unwrap();
+        assert_eq!(config.settings().ownership, Ownership::Passwd(IdMap { users: vec![(0, 1000)].into_iter().collect(), groups: Default::default() }));
+        assert!(config.set("ownership", "nobody").is_err());
+        assert!(config.set("gid_map", "100").is_err());
+        config.set("threads", "0").unwrap();
+        assert_eq!(config.threads, 0);
+        assert!(config.set("threads", "-1").is_err());
+        assert_eq!(config.settings().ttl, Timespec { sec: 0, nsec: 500_000_000 });
+        assert_eq!(config.settings().attr_ttl, Timespec { sec: 0, nsec: 500_000_000 });
+        config.set("attr_ttl", "30").unwrap();
+        assert_eq!(config.settings().attr_ttl, Timespec { sec: 30, nsec: 0 });
+        assert!(config.set("attr_ttl", "forever").is_err());
+        assert_eq!(config.allow, Allow { unlink: true, rmdir: false });
+        assert_eq!(config.settings().deny_errno, EROFS);
+        assert_eq!(config.branches, vec![PathBuf::from("/mnt/disk2")]);
+        assert_eq!(config.precedence, Precedence::Newest);
+        assert!(config.set("ttl", "-1").is_err());
+        assert!(config.set("precedence", "last").is_err());
+        config.set("exclude", "/lost+found").unwrap();
+        assert!(!config.settings().filter.is_visible(Path::new("lost+found")));
+        assert!(config.set("include", "[a").is_err());
+        assert!(config.set("policy", "write").is_err());
+        assert!(config.set("deny_errno", "ENOENT").is_err());
+        assert!(config.set("bogus", "1").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Process management: daemonizing, pidfiles, and signal-driven unmounting.
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+use std::thread::{self, JoinHandle};
+
+use libc::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::session::unmount;
+
+/// Directory the program was started in, which `daemonize` leaves for `/`.
+static STARTED_IN: OnceLock<PathBuf> = OnceLock::new();
+
+/// `path`, relative to the directory the program was started in even once daemonized.
+pub fn absolute(path: &Path) -> io::Result<PathBuf> {
+    match STARTED_IN.get() {
+        Some(dir) => Ok(dir.join(path)),
+        None => Ok(env::current_dir()?.join(path))
+    }
+}
+
+/// Handle held by a daemonized child, used to report the outcome of mounting to the parent.
+#[derive(Debug)]
+pub struct Daemon {
+    notify: File,
+}
+
+/// Detach from the controlling terminal, running the remainder of the program in a child process.
+///
+/// The parent waits until the child calls `Daemon::ready`, and exits with a status reflecting
+/// whether the mount succeeded, so callers (and init systems) see mount failures.
+/// Must be called before any threads are started.
+pub fn daemonize() -> io::Result<Daemon> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut status, notify) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => (),
+        _ => {
+            drop(notify);
+            let mut message = String::new();
+            let _ = status.read_to_string(&mut message);
+            match message.as_str() {
+                "" => {
+                    eprintln!("decofs: daemon exited before mounting");
+                    process::exit(1);
+                },
+                "ok" => process::exit(0),
+                message => {
+                    eprintln!("decofs: {}", message);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    drop(status);
+
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Fork again, so that the daemon can never reacquire a controlling terminal.
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => (),
+        _ => process::exit(0),
+    }
+    let _ = STARTED_IN.set(env::current_dir()?);
+    env::set_current_dir("/")?;
+    let null = CString::new("/dev/null").unwrap();
+    unsafe {
+        let fd = libc::open(null.as_ptr(), libc::O_RDWR);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for target in 0..3 {
+            libc::dup2(fd, target);
+        }
+        if fd > 2 {
+            libc::close(fd);
+        }
+    }
+    Ok(Daemon { notify })
+}
+
+impl Daemon {
+    /// Report the outcome of mounting to the waiting parent process, which then exits.
+    pub fn ready(mut self, result: &Result<(), String>) {
+        let message = match result {
+            Ok(()) => "ok",
+            Err(e) => e.as_str(),
+        };
+        let _ = self.notify.write_all(message.as_bytes());
+    }
+}
+
+/// Raise the limit on open files to the maximum permitted, returning the new limit.
+///
+/// Every inode the kernel references holds a descriptor on its source file, so a large directory
+/// tree can exceed the usual default of 1024.
+pub fn raise_open_files_limit() -> io::Result<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    limit.rlim_cur = limit.rlim_max;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit.rlim_cur)
+}
+
+/// File containing the process id, removed when dropped.
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Write the current process id to `path`, which if relative is taken from the directory the
+    /// program was started in.
+    pub fn create(path: &Path) -> io::Result<Pidfile> {
+        let path = absolute(path)?;
+        fs::write(&path, format!("{}\n", process::id()))?;
+        Ok(Pidfile { path })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove pidfile {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Handles SIGTERM/SIGINT by unmounting `mountpoint`, which ends the session loop, and SIGHUP by
+/// calling `reload`.
+///
+/// The returned thread finishes once `Signals::close` is called on the returned `Signals`.
+pub fn handle_signals<F>(mountpoint: &Path, reload: F) -> io::Result<(Signals, JoinHandle<()>)>
+where F: Fn() + Send + 'static {
+    let signals = Signals::new([SIGHUP, SIGINT, SIGTERM])?;
+    let mountpoint = mountpoint.to_path_buf();
+    let handle = {
+        let signals = signals.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                match signal {
+                    SIGHUP => {
+                        info!("SIGHUP: reloading configuration");
+                        reload();
+                    },
+                    _ => {
+                        info!("signal {}: unmounting {:?}", signal, mountpoint);
+                        if let Err(e) = unmount(&mountpoint) {
+                            error!("Failed to unmount {:?}: {}", mountpoint, e);
+                        }
+                    }
+                }
+            }
+        })
+    };
+    Ok((signals, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_pidfile() {
+        let name = format!("decofs-test-{}.pid", process::id());
+        let path = env::current_dir().unwrap().join(&name);
+        let pidfile = Pidfile::create(Path::new(&name)).unwrap();
+        assert_eq!(pidfile.path, path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
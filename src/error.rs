@@ -0,0 +1,84 @@
+//! Errors serving a request, and the error code the kernel is sent for each.
+use std::ffi::NulError;
+use std::fmt;
+use std::io;
+
+use libc::{c_int, EACCES, EEXIST, EINVAL, EIO, ENOENT, ENOTEMPTY, ETIMEDOUT};
+
+/// Failure to serve a request.
+#[derive(Debug)]
+pub(crate) enum DecoError {
+    /// An error code, replied as it is (e.g. a denial by the policy, or a hidden file).
+    Errno(c_int),
+    /// An error from the source.
+    Io(io::Error),
+    /// A name containing a NUL byte, which cannot be passed to the kernel.
+    Nul(NulError),
+}
+
+/// Error code for `error`, which may not have come from the kernel (e.g. one made by std).
+pub(crate) fn errno(error: &io::Error) -> c_int {
+    error.raw_os_error().unwrap_or_else(|| match error.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::TimedOut => ETIMEDOUT,
+        _ => EIO
+    })
+}
+
+impl DecoError {
+    /// Error code replied to the kernel.
+    pub(crate) fn errno(&self) -> c_int {
+        match self {
+            DecoError::Errno(e) => *e,
+            DecoError::Io(e) => errno(e),
+            DecoError::Nul(_) => EINVAL,
+        }
+    }
+}
+
+impl fmt::Display for DecoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecoError::Errno(e) => write!(f, "{}", io::Error::from_raw_os_error(*e)),
+            DecoError::Io(e) => write!(f, "{}", e),
+            DecoError::Nul(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<c_int> for DecoError {
+    fn from(errno: c_int) -> DecoError {
+        DecoError::Errno(errno)
+    }
+}
+
+impl From<io::Error> for DecoError {
+    fn from(error: io::Error) -> DecoError {
+        DecoError::Io(error)
+    }
+}
+
+impl From<NulError> for DecoError {
+    fn from(error: NulError) -> DecoError {
+        DecoError::Nul(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn errnos() {
+        assert_eq!(DecoError::from(ENOENT).errno(), ENOENT);
+        assert_eq!(DecoError::from(io::Error::from_raw_os_error(EACCES)).errno(), EACCES);
+        assert_eq!(DecoError::from(io::Error::new(io::ErrorKind::NotFound, "gone")).errno(), ENOENT);
+        assert_eq!(DecoError::from(io::Error::other("odd")).errno(), EIO);
+        assert_eq!(DecoError::from(CString::new("a\0b").unwrap_err()).errno(), EINVAL);
+    }
+}
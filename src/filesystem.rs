@@ -0,0 +1,1018 @@
+//! The pass-through filesystem itself.
+use std::{fs,io};
+use std::path::{Path, PathBuf};
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::Ordering;
+use std::panic::{self, AssertUnwindSafe};
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use libc::{c_int, EBADF, ENOENT, ENOTSUP, EOPNOTSUPP, ESTALE, O_NOFOLLOW, O_PATH, O_RDONLY};
+use time::Timespec;
+use std::os::unix::ffi::OsStrExt;
+
+use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyStatfs, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyWrite, ReplyCreate, ReplyLock, ReplyBmap, ReplyXattr};
+
+use crate::attributes::Attributes;
+use crate::config::Settings;
+use crate::error::{self, DecoError};
+use crate::hook::Hook;
+use crate::inodes::{self, Handle, InodeTable, SourceId};
+use crate::invalidate::Invalidator;
+use crate::open_files::{OpenFile, OpenFiles};
+use crate::policy::{Decision, Operation, OperationPolicy, Requester};
+use crate::session::Ready;
+use crate::stats::Stats;
+use crate::store::InodeStore;
+use crate::symlinks::{self, Symlinks};
+use crate::union::{Precedence, Union};
+use crate::watcher::{Change, Kind, Watcher};
+use crate::workers::Workers;
+
+/// Trait to assign to Reply* types, for commonality of error methods.
+trait FuseError {
+    /// Reply to a request with the error code for `error`.
+    fn fuse_error<E: Into<DecoError>>(self, error: E);
+}
+
+/// Macro to add FuseError trait
+macro_rules! add_fuse_error {
+    ($type: ty) => {
+        impl FuseError for $type {
+            fn fuse_error<E: Into<DecoError>>(self, error: E) {
+                self.error(error.into().errno());
+            }
+        }
+    }
+}
+add_fuse_error!(ReplyEntry);
+add_fuse_error!(ReplyEmpty);
+add_fuse_error!(ReplyAttr);
+add_fuse_error!(ReplyWrite);
+add_fuse_error!(ReplyOpen);
+add_fuse_error!(ReplyData);
+add_fuse_error!(ReplyStatfs);
+add_fuse_error!(ReplyCreate);
+add_fuse_error!(ReplyXattr);
+add_fuse_error!(ReplyDirectory);
+add_fuse_error!(ReplyLock);
+add_fuse_error!(ReplyBmap);
+
+/// Pass-through filesystem exposing a union of source directories, built with `DecoFsBuilder`.
+///
+/// Requests are received on the session thread, and served concurrently by a pool of workers.
+#[derive(Debug)]
+pub struct DecoFS {
+    inner: Arc<Inner>,
+    workers: Workers,
+    inode_store: Option<PathBuf>,
+    watch: bool,
+    watcher: Option<Watcher>
+}
+
+/// State of the filesystem, shared by the workers serving requests.
+#[derive(Debug)]
+struct Inner {
+    inodes: RwLock<InodeTable>,
+    union: Union,
+    settings: Arc<RwLock<Settings>>,
+    policy: Box<dyn OperationPolicy>,
+    hooks: Vec<Box<dyn Hook>>,
+    files: OpenFiles,
+    ready: Arc<Ready>,
+    stats: Arc<Stats>
+}
+
+impl DecoFS {
+    pub(crate) fn new(sourceroots: Vec<PathBuf>, precedence: Precedence, settings: Arc<RwLock<Settings>>, policy: Box<dyn OperationPolicy>, hooks: Vec<Box<dyn Hook>>, threads: usize, inode_store: Option<PathBuf>) -> DecoFS {
+        let union = Union::new(sourceroots, precedence);
+        let root = fs::metadata(union.resolve(Path::new(""))).ok().map(|attr| (attr.st_dev(), attr.st_ino()));
+        let stats = Arc::new(Stats::default());
+        let inodes = RwLock::new(InodeTable::new(root, stats.clone()));
+        let files = OpenFiles::new(stats.clone());
+        let inner = Inner { inodes, union, settings, policy, hooks, files, ready: Default::default(), stats };
+        DecoFS { inner: Arc::new(inner), workers: Workers::new(threads), inode_store, watch: false, watcher: None }
+    }
+
+    /// Watch the sources once mounted, if `watch`.
+    pub(crate) fn watching(mut self, watch: bool) -> DecoFS {
+        self.watch = watch;
+        self
+    }
+
+    /// Open the inode store, if one was configured, so that inode numbers persist across mounts.
+    pub(crate) fn open_inode_store(&mut self) -> io::Result<()> {
+        if let Some(path) = self.inode_store.take() {
+            let store = InodeStore::open(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("inode store {:?}: {}", path, e)))?;
+            info!("inode store {:?}, next inode {}", path, store.next());
+            self.inner.inodes_mut().persist(store);
+        }
+        Ok(())
+    }
+
+    /// Start watching the sources, if configured to, for changes made other than through the mount,
+    /// which the kernel is told to drop from its cache through `connection`, the session's
+    /// descriptor on `/dev/fuse`.
+    ///
+    /// Without one, entries and attributes the kernel has cached are presented until their TTLs
+    /// expire. Listings are never cached.
+    pub(crate) fn watch_sources(&mut self, connection: Option<RawFd>) -> io::Result<()> {
+        if self.watch && self.watcher.is_none() {
+            let invalidator = match connection.map(Invalidator::new) {
+                Some(Ok(invalidator)) => Some(invalidator),
+                Some(Err(e)) => {
+                    warn!("the kernel will not be told of changes on the source: {}", e);
+                    None
+                },
+                None => {
+                    warn!("the kernel will not be told of changes on the source: its connection was not found");
+                    None
+                }
+            };
+            let inner = self.inner.clone();
+            self.watcher = Some(Watcher::new(self.inner.union.branches(), move |change| inner.source_changed(change, invalidator.as_ref()))?);
+        }
+        Ok(())
+    }
+
+    /// Metrics, updated while the filesystem is served.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.inner.stats.clone()
+    }
+    pub(crate) fn ready(&self) -> Arc<Ready> {
+        self.inner.ready.clone()
+    }
+
+    /// Release resources at the end of the session.
+    ///
+    /// The kernel only sends DESTROY for some mounts, so this is also called once the session
+    /// loop ends.
+    pub(crate) fn teardown(&mut self) {
+        info!("destroy, with {} inodes", self.inner.inodes().len());
+        self.watcher = None;
+    }
+
+    /// Serve a request on a worker.
+    ///
+    /// A request which panics fails alone: fuse replies `EIO` in place of the reply it dropped.
+    fn spawn<F>(&self, f: F) where F: FnOnce(&Inner) + Send + 'static {
+        let inner = self.inner.clone();
+        self.workers.run(move || {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| f(&inner))) {
+                let message = panic.downcast_ref::<&str>().copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown cause");
+                error!("request failed, replying EIO: {}", message);
+            }
+        });
+    }
+}
+
+impl Inner {
+    fn ttl(&self) -> Timespec {
+        self.settings.read().unwrap().ttl
+    }
+    fn attr_ttl(&self) -> Timespec {
+        self.settings.read().unwrap().attr_ttl
+    }
+
+    /// The inode table, even if a request panicked while updating it: the update may be left
+    /// incomplete, but failing every later request (and `forget`, on the session thread) would
+    /// end the mount.
+    fn inodes(&self) -> RwLockReadGuard<'_, InodeTable> {
+        self.inodes.read().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn inodes_mut(&self) -> RwLockWriteGuard<'_, InodeTable> {
+        self.inodes.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Release `nlookup` references from the kernel to `ino`.
+    fn forget(&self, ino: u64, nlookup: u64) {
+        self.inodes_mut().forget(ino, nlookup);
+    }
+
+    /// Forget the name of a source file created or removed other than through the mount, which
+    /// may no longer lead to the inode it did, and have the kernel drop what it has cached of the
+    /// name, its directory, and a file modified.
+    fn source_changed(&self, change: Change, invalidator: Option<&Invalidator>) {
+        self.stats.source_changes.fetch_add(1, Ordering::Relaxed);
+        let (parent, ino) = {
+            let mut inodes = self.inodes_mut();
+            let parent = change.path.parent().and_then(|parent| inodes.ino(parent));
+            let ino = match change.kind {
+                Kind::Created | Kind::Removed => inodes.unlink(&change.path),
+                Kind::Modified => inodes.ino(&change.path)
+            };
+            (parent, ino)
+        };
+        if let Some(ino) = ino {
+            info!("{:?} {:?} on the source, which was inode {}", change.path, change.kind, ino);
+        }
+        // Sent with the table unlocked, as the kernel first waits for requests on the directory.
+        let invalidator = match invalidator {
+            Some(invalidator) => invalidator,
+            None => return
+        };
+        let result = match (change.kind, parent, change.path.file_name(), ino) {
+            (Kind::Modified, _, _, Some(ino)) => invalidator.inode(ino),
+            (Kind::Created | Kind::Removed, Some(parent), Some(name), _) => invalidator.entry(parent, name)
+                .and_then(|()| invalidator.inode(parent)),
+            _ => Ok(())
+        };
+        if let Err(e) = result {
+            warn!("failed to invalidate {:?}: {}", change.path, e);
+        }
+    }
+    /// Attributes presented for the source file `path`, following the symlink policy: those of a
+    /// link, or of the file it leads to when followed; `None` if hidden.
+    fn present(&self, path: &Path) -> io::Result<Option<Attributes>> {
+        let symlinks = self.settings.read().unwrap().symlinks;
+        let attr = self.union.stat(path, symlinks == Symlinks::Follow)?;
+        if !attr.is_symlink() {
+            return Ok(Some(attr));
+        }
+        match symlinks {
+            Symlinks::Expose | Symlinks::Rewrite => Ok(Some(attr)),
+            Symlinks::Hide => {
+                info!("{:?} hidden, being a link", path);
+                Ok(None)
+            },
+            Symlinks::Follow => match self.union.open(path, O_PATH, true) {
+                Ok(file) => Attributes::of_file(&file).map(Some),
+                Err(e) => {
+                    info!("{:?} hidden, leading outside the sources or nowhere: {}", path, e);
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    /// Attributes of `path`, and its identity in the source tree, from which the caller
+    /// determines the inode number.
+    fn stat(&self, path: &Path) -> io::Result<(SourceId, FileAttr)> {
+      info!("stat {:?}", path);
+      let attr = self.present(path)?.ok_or_else(|| io::Error::from_raw_os_error(ENOENT))?;
+      Ok((attr.id(), self.file_attr(&attr)))
+    }
+
+    /// Attributes presented for a source file with attributes `attr`, but for the inode number.
+    fn file_attr(&self, attr: &Attributes) -> FileAttr {
+      let (uid, gid) = {
+        let settings = self.settings.read().unwrap();
+        settings.ownership.present(attr.uid, attr.gid, &settings.accounts)
+      };
+
+      let file_attr = FileAttr {
+        ino: 0,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime,
+        mtime: attr.mtime,
+        ctime: attr.ctime,
+        // Only presented by FUSE on macOS; the kernel's attributes have no creation time on Linux.
+        crtime: attr.btime.unwrap_or(Timespec {sec: 0, nsec: 0}),
+        kind: attr.kind(),
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink,
+        uid,
+        gid,
+        rdev: attr.rdev as u32,
+        flags: 0,
+      };
+      info!("file_attr {:?}, immutable {}, append {}, mount {:?}", file_attr, attr.immutable, attr.append, attr.mount_id);
+      file_attr
+    }
+
+    /// Path relative to the root of the mount, and source path, of a known inode.
+    ///
+    /// An inode with a handle is wherever its source file now is, even if renamed on the source,
+    /// and `ESTALE` once removed. Of several names (hard links), the most recently found one which
+    /// still leads to the inode is used, so that the file remains reachable after any other is
+    /// removed.
+    fn locate(&self, ino: u64) -> Result<(PathBuf, PathBuf), c_int> {
+        // Copied out, so that the table is not locked while the source is consulted.
+        let (paths, id, handle) = {
+            let inodes = self.inodes();
+            (inodes.paths(ino).map(Path::to_path_buf).collect::<Vec<_>>(), inodes.id(ino), inodes.handle(ino))
+        };
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let leads_to_inode = |path: &&Path| match (self.present(&self.union.resolve(path)), id) {
+            (Ok(Some(attr)), Some(id)) => attr.id() == id,
+            _ => false
+        };
+        let path = match handle.map(|handle| handle.path()) {
+            Some(Ok(real)) => match self.union.locate(&real) {
+                Some(located) => return Ok(located),
+                None => {
+                    info!("inode {} moved outside the sources, to {:?}", ino, real);
+                    return Err(ESTALE);
+                }
+            },
+            // The name the handle was opened by has been removed, but another may remain.
+            Some(Err(_)) => match paths.iter().copied().find(leads_to_inode) {
+                Some(path) => path,
+                None => {
+                    info!("inode {} removed from the sources", ino);
+                    return Err(ESTALE);
+                }
+            },
+            None => {
+                let path = match paths.len() {
+                    0 => None,
+                    1 => paths.first().copied(),
+                    _ => paths.iter().copied().find(leads_to_inode).or_else(|| paths.first().copied())
+                };
+                match path {
+                    Some(path) => path,
+                    None => {
+                        info!("ino_to_path {} ENOENT", ino);
+                        return Err(ENOENT);
+                    }
+                }
+            }
+        };
+        Ok((path.to_path_buf(), self.union.resolve(path)))
+    }
+
+    /// Path, relative to the root of the mount, of a known inode.
+    fn ino_to_mount_path(&self, ino: u64) -> Result<PathBuf, c_int> {
+        self.locate(ino).map(|(path, _)| path)
+    }
+
+    fn ino_to_path(&self, ino: u64) -> Result<PathBuf, c_int> {
+        info!("ino_to_path {}", ino);
+        let (_, path) = self.locate(ino)?;
+        info!("ino_to_path {} -> {:?}", ino, path);
+        Ok(path)
+    }
+
+    /// Inode for the source file `id`, found at `path` relative to the root of the mount and at
+    /// `source`, counting a reference from the kernel.
+    fn retain(&self, id: SourceId, path: PathBuf, source: &Path, handle: Option<Handle>) -> u64 {
+        // Opened before locking the table, so that a slow disk does not hold up other requests.
+        let handle = match handle {
+            Some(handle) => Some(handle),
+            None if self.inodes().retains(id) => None,
+            None => match self.open_handle(source, id) {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    warn!("no handle on {:?}, following it by name: {}", source, e);
+                    None
+                }
+            }
+        };
+        self.inodes_mut().lookup(id, path, || handle)
+    }
+
+    /// Whether symbolic links within the sources are followed when resolving source paths.
+    fn follows_links(&self) -> bool {
+        self.settings.read().unwrap().symlinks == Symlinks::Follow
+    }
+
+    /// Handle on the source file `id` at `source`; a followed link's inode is the file it leads to.
+    fn open_handle(&self, source: &Path, id: SourceId) -> io::Result<Handle> {
+        let follow = self.follows_links();
+        let flags = match follow {
+            true => O_PATH,
+            false => O_PATH | O_NOFOLLOW
+        };
+        Handle::new(self.union.open(source, flags, follow)?, id)
+    }
+
+    /// Drop the name `name` in `parent` from the inode table, once removed from the source.
+    fn forget_name(&self, parent: u64, name: &OsStr) {
+        if let Ok(path) = self.ino_to_mount_path(parent) {
+            if let Some(ino) = self.inodes_mut().unlink(&path.join(name)) {
+                info!("unlinked {:?} from inode {}", path.join(name), ino);
+            }
+        }
+    }
+
+    /// Whether `path`, relative to the root of the mount, is exposed by the filter.
+    fn is_visible(&self, path: &Path) -> bool {
+        self.settings.read().unwrap().filter.is_visible(path)
+    }
+
+    /// Whether `source` is on device `dev`, but its parent is not, when confined to one filesystem.
+    fn crosses_device(&self, source: &Path, dev: u64) -> bool {
+        if !self.settings.read().unwrap().one_file_system {
+            return false;
+        }
+        match source.parent().map(|parent| self.present(parent)) {
+            Some(Ok(Some(parent))) if parent.dev != dev => {
+                info!("{:?} is on another device", source);
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Source path of `path`, relative to the root of the mount, unless hidden by the filter.
+    fn mount_to_source_path(&self, path: &Path) -> Result<PathBuf, c_int> {
+        match self.is_visible(path) {
+            true => Ok(self.union.resolve(path)),
+            false => {
+                info!("{:?} hidden by filter", path);
+                Err(ENOENT)
+            }
+        }
+    }
+
+    fn get_source_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf, c_int> {
+        self.mount_to_source_path(&self.ino_to_mount_path(parent)?.join(name))
+    }
+
+    /// Consult the policy as to whether `operation` may be applied to `path`, with the attributes
+    /// of the file to be deleted, for a deletion.
+    fn permit(&self, operation: Operation, requester: &Requester, path: &Path) -> Result<(), c_int> {
+        let attributes = match operation {
+            Operation::Unlink | Operation::Rmdir => self.union.stat(path, self.follows_links()).ok(),
+            _ => None
+        };
+        match self.policy.check(operation, path, attributes.as_ref(), requester) {
+            Decision::Allow => Ok(()),
+            Decision::Deny(e) => {
+                info!("{:?} {:?} denied for {:?}: {}", operation, path, requester, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn resolve_path(&self, operation: Operation, requester: &Requester, parent: u64, name: &OsStr) -> Result<PathBuf, c_int> {
+        let path = self.get_source_path(parent, name)?;
+        self.permit(operation, requester, &path)?;
+        Ok(path)
+    }
+
+    fn resolve_ino(&self, operation: Operation, requester: &Requester, ino: u64) -> Result<PathBuf, c_int> {
+        let path = self.ino_to_path(ino)?;
+        self.permit(operation, requester, &path)?;
+        Ok(path)
+    }
+
+    /// Tell each hook that `operation` has been applied to `path`.
+    fn notify<R>(&self, operation: Operation, requester: &Requester, path: &Path, result: &io::Result<R>) {
+        let result = result.as_ref().map(|_| ()).map_err(error::errno);
+        for hook in &self.hooks {
+            hook.completed(operation, requester, path, result);
+        }
+    }
+
+    fn apply_to_path<T: FuseError, F>(&self, operation: Operation, requester: &Requester, parent: u64, name: &OsStr, reply: T, f: F) where F:Fn(PathBuf, T) {
+        match self.resolve_path(operation, requester, parent, name) {
+            Ok(path) => f(path, reply),
+            Err(e) => reply.fuse_error(e)
+        }
+    }
+
+    fn apply_to_ino<T: FuseError, F>(&self, operation: Operation, requester: &Requester, ino: u64, reply: T, f: F) where F:Fn(PathBuf, T) {
+        match self.resolve_ino(operation, requester, ino) {
+            Ok(path) => f(path, reply),
+            Err(e) => reply.fuse_error(e)
+        }
+    }
+
+    /// Apply `f` to the file open with handle `fh`, if the policy permits `operation` on the path
+    /// at which it was opened; it may since have been renamed or removed.
+    fn apply_to_fh<T: FuseError, F>(&self, operation: Operation, requester: &Requester, fh: u64, reply: T, f: F) where F: Fn(&OpenFile, T) {
+        let file = match self.files.get(fh) {
+            Some(file) => file,
+            None => {reply.fuse_error(EBADF);return;}
+        };
+        match self.permit(operation, requester, &file.path) {
+            Ok(()) => f(&file, reply),
+            Err(e) => reply.fuse_error(e)
+        }
+    }
+
+    /// Attributes of `ino`, or, once removed from the source, of a file still open by it, which
+    /// the kernel asks for before reading it.
+    fn getattr(&self, requester: &Requester, ino: u64, reply: ReplyAttr) {
+        let stat = || -> Result<FileAttr, DecoError> {
+            match self.resolve_ino(Operation::Getattr, requester, ino) {
+                Ok(path) => Ok(self.stat(&path)?.1),
+                Err(ESTALE) => {
+                    let file = self.files.of_inode(ino).ok_or(ESTALE)?;
+                    self.permit(Operation::Getattr, requester, &file.path)?;
+                    Ok(self.file_attr(&file.attributes()?))
+                },
+                Err(e) => Err(e.into())
+            }
+        };
+        match stat() {
+            Ok(stat) => reply.attr(&self.attr_ttl(), &FileAttr { ino, ..stat }),
+            Err(e) => reply.fuse_error(e)
+        }
+    }
+
+    fn lookup(&self, requester: &Requester, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        info!("lookup {} {:?}", parent, name);
+        // The parent is located once, for both the source path and the path in the mount.
+        let resolve = || -> Result<(PathBuf, PathBuf), c_int> {
+            let mount_path = self.ino_to_mount_path(parent)?.join(name);
+            let path = self.mount_to_source_path(&mount_path)?;
+            self.permit(Operation::Lookup, requester, &path)?;
+            Ok((mount_path, path))
+        };
+        let (mount_path, path) = match resolve() {
+            Ok(paths) => paths,
+            Err(e) => {reply.fuse_error(e);return;}
+        };
+        match self.stat(&path) {
+            Ok((id, _)) if self.crosses_device(&path, id.0) => reply.fuse_error(ENOENT),
+            Ok((id, mut stat)) => {
+                stat.ino = self.retain(id, mount_path, &path, None);
+                reply.entry(&self.ttl(), &stat, 0);
+                },
+            Err(e) => reply.fuse_error(e)
+        }
+    }
+
+    fn unlink(&self, requester: &Requester, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("unlink {:?} {:?}", parent, name);
+        let path = match self.resolve_path(Operation::Unlink, requester, parent, name) {
+            Ok(path) => path,
+            Err(e) => {reply.fuse_error(e);return;}
+        };
+        let result = self.union.remove_file(&path, self.follows_links());
+        self.notify(Operation::Unlink, requester, &path, &result);
+        match result {
+            Ok(_) => {
+                self.forget_name(parent, name);
+                reply.ok()
+            },
+            Err(e) => reply.fuse_error(e)
+        }
+    }
+
+    fn rmdir(&self, requester: &Requester, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("rmdir {:?} {:?}", parent, name);
+        let path = match self.resolve_path(Operation::Rmdir, requester, parent, name) {
+            Ok(path) => path,
+            Err(e) => {reply.fuse_error(e);return;}
+        };
+        let result = self.union.remove_dir(&path, self.follows_links());
+        self.notify(Operation::Rmdir, requester, &path, &result);
+        match result {
+            Ok(_) => {
+                self.forget_name(parent, name);
+                reply.ok()
+            },
+            Err(e) => reply.fuse_error(e)
+        }
+    }
+
+    fn readdir(&self, requester: &Requester, ino: u64, offset: i64, mut reply: ReplyDirectory) {
+        info!("readdir {} {}", ino, offset);
+        if let Err(e) = self.resolve_ino(Operation::Readdir, requester, ino) {
+            reply.fuse_error(e);
+            return;
+        }
+        let root = match self.ino_to_mount_path(ino) {
+            Ok(root) => root,
+            Err(e) => {reply.fuse_error(e);return;}
+        };
+        let listing = match self.union.read_dir(&root) {
+            Ok(listing) => listing,
+            Err(e) => {reply.fuse_error(e);return;}
+        };
+        let mut listed = Vec::new();
+        for (name, path) in listing {
+            if !self.is_visible(&root.join(&name)) {
+                continue;
+            }
+            // An entry which cannot be presented (e.g. removed since listed) is left out, rather
+            // than failing the whole listing.
+            let attr = match self.present(&path) {
+                Ok(Some(attr)) => attr,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("{:?} not listed: {}", path, e);
+                    continue;
+                }
+            };
+            if self.crosses_device(&path, attr.dev) {
+                continue;
+            }
+            // A number recorded in the store is only given for the same file, as `lookup` would;
+            // the generation telling them apart is found before locking the table.
+            let generation = match self.inodes().recorded(attr.id()) {
+                true => self.open_handle(&path, attr.id()).ok().map(|handle| handle.generation()),
+                false => None
+            };
+            listed.push((attr.id(), generation, attr.kind(), name));
+        }
+        let mut entries = vec![ (ino, FileType::Directory, OsString::from(".")), (inodes::ROOT, FileType::Directory, OsString::from("..")) ];
+        {
+            // The kernel does not reference entries listed here, so they are not retained, but
+            // given the numbers a lookup of them will.
+            let mut inodes = self.inodes_mut();
+            entries.extend(listed.into_iter().map(|(id, generation, file_type, name)| (inodes.peek(id, generation), file_type, name)));
+        }
+        info!("entries: {:?}", entries);
+
+        // Offset of 0 means no offset.
+        // Non-zero offset means the passed offset has already been seen, and we should start after
+        // it.
+        let to_skip = if offset == 0 { offset } else { offset + 1 } as usize;
+        for (i, entry) in entries.into_iter().enumerate().skip(to_skip) {
+            info!("reply {}, {}, {:?}, {:?}", entry.0, i as i64, entry.1, entry.2);
+            let r = reply.add(entry.0, i as i64, entry.1, entry.2);
+            info!("r {}", r);
+        }
+        reply.ok();
+    }
+}
+
+impl Filesystem for DecoFS {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        info!("init");
+        self.inner.ready.set();
+        Ok(())
+    }
+    fn destroy(&mut self, _req: &Request) {
+        self.teardown();
+    }
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.lookup(&requester, parent, &name, reply));
+    }
+    /// Not put to the policy, as no operation is applied to the source, and the kernel cannot be
+    /// refused: it has already dropped its references.
+    ///
+    /// The kernel only sends BATCH_FORGET with protocol 7.16 onwards, and fuse negotiates 7.8, so
+    /// every release of references arrives here.
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        info!("forget {} {}", ino, nlookup);
+        self.inner.forget(ino, nlookup);
+    }
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        info!("getattr {:?}", ino);
+        let requester = req.into();
+        self.spawn(move |fs| fs.getattr(&requester, ino, reply));
+    }
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        info!("readlink {:?}", ino);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Readlink, &requester, ino, reply, |path, reply| match fs.union.read_link(&path, fs.follows_links()) {
+            Ok(target) => match fs.settings.read().unwrap().symlinks {
+                Symlinks::Rewrite => match fs.ino_to_mount_path(ino) {
+                    Ok(link) => reply.data(symlinks::confine(&link, &target).as_os_str().as_bytes()),
+                    Err(e) => reply.fuse_error(e)
+                },
+                _ => reply.data(target.as_os_str().as_bytes())
+            },
+            Err(e) => reply.fuse_error(e)
+        }));
+    }
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.unlink(&requester, parent, &name, reply));
+    }
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.rmdir(&requester, parent, &name, reply));
+    }
+    fn open(&mut self, req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        info!("open {:?}", ino);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Open, &requester, ino, reply, |path, reply| {
+            // Only ever read: writes are refused by the policy, or unimplemented.
+            match fs.union.open(&path, O_RDONLY, fs.follows_links()) {
+                Ok(file) => reply.opened(fs.files.insert(ino, file, path), 0),
+                Err(e) => reply.fuse_error(e)
+            }
+        }));
+    }
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        info!("read {:?} {} {} {}", ino, fh, offset, size);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_fh(Operation::Read, &requester, fh, reply, |file, reply| {
+            match file.read(offset as u64, size as usize) {
+                Ok(buffer) => reply.data(&buffer),
+                Err(e) => reply.fuse_error(e)
+            }
+        }));
+    }
+    fn flush(&mut self, req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_fh(Operation::Flush, &requester, fh, reply, |_file, reply| reply.ok()));
+    }
+    /// The file is closed whatever the policy decides, as the kernel has closed it already.
+    fn release(&mut self, req: &Request, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        info!("release {} {}", ino, fh);
+        let requester = req.into();
+        self.spawn(move |fs| match fs.files.remove(fh) {
+            Some(file) => match fs.permit(Operation::Release, &requester, &file.path) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.fuse_error(e)
+            },
+            None => reply.fuse_error(EBADF)
+        });
+    }
+    fn fsync(&mut self, req: &Request, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_fh(Operation::Fsync, &requester, fh, reply, |_file, reply| reply.ok()));
+    }
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Opendir, &requester, ino, reply, |_path, reply| reply.opened(0, 0)));
+    }
+    fn readdir(&mut self, req: &Request, ino: u64, _fh: u64, offset: i64, reply: ReplyDirectory) {
+        let requester = req.into();
+        self.spawn(move |fs| fs.readdir(&requester, ino, offset, reply));
+    }
+    /// Not put to the policy, as nothing is opened by `opendir` and the kernel has already
+    /// closed the directory.
+    fn releasedir(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
+        reply.ok();
+    }
+    fn fsyncdir(&mut self, req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Fsyncdir, &requester, ino, reply, |_path, reply| reply.ok()));
+    }
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        info!("statfs {:?}", ino);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Statfs, &requester, ino, reply, |_path, reply| {
+            match fs.union.statfs() {
+                Ok(stat) => reply.statfs(stat.blocks, stat.bfree, stat.bavail, stat.files, stat.ffree, stat.bsize, stat.namelen, stat.frsize),
+                Err(e) => reply.fuse_error(e)
+            }
+        }));
+    }
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, _size: u32, reply: ReplyXattr) {
+        info!("getxattr {:?} {:?}", ino, name);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Getxattr, &requester, ino, reply, |_path, reply| {
+            reply.fuse_error(ENOTSUP);
+        }));
+    }
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        info!("listxattr {:?} {}", ino, size);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Listxattr, &requester, ino, reply, |path, reply| {
+            match size {
+                0 => match fs.union.listxattr_size(&path, fs.follows_links()) {
+                    Ok(size) => reply.size(size as u32),
+                    Err(e) => reply.fuse_error(e)
+                },
+                // TODO implement
+                _ => reply.fuse_error(ENOENT)
+            }
+        }));
+    }
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        info!("access {} {}", ino, mask);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Access, &requester, ino, reply, |_path, reply| reply.ok()));
+    }
+    fn getlk(&mut self, req: &Request, ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, reply: ReplyLock) {
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Getlk, &requester, ino, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    fn bmap(&mut self, req: &Request, ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Bmap, &requester, ino, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    // Disabled functionality: denied by the default policy, and unsupported (EOPNOTSUPP) if
+    // permitted. Not ENOSYS, which the kernel remembers for some operations (e.g. setxattr), so
+    // that they would go on failing once the policy is reloaded.
+    /// For this deco filesystem, we do not support setting attributes.
+    fn setattr(&mut self, req: &Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        info!("setattr {}", ino);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Setattr, &requester, ino, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support creating nodes (regular file, character device, block device, fifo or socket).
+    fn mknod(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+        info!("mknod {} {:?}", parent, name);
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.apply_to_path(Operation::Mknod, &requester, parent, &name, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support creating directories.
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        info!("mkdir {} {:?}", parent, name);
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.apply_to_path(Operation::Mkdir, &requester, parent, &name, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support creating symbolic links.
+    fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, _link: &Path, reply: ReplyEntry) {
+        info!("symlink {} {:?}", parent, name);
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.apply_to_path(Operation::Symlink, &requester, parent, &name, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support renaming files.
+    fn rename(&mut self, req: &Request, parent: u64, name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
+        info!("rename {} {:?}", parent, name);
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.apply_to_path(Operation::Rename, &requester, parent, &name, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support creating hard links.
+    fn link(&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        info!("link {} {} {:?}", ino, newparent, newname);
+        let (requester, newname) = (req.into(), newname.to_os_string());
+        self.spawn(move |fs| fs.apply_to_path(Operation::Link, &requester, newparent, &newname, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support writing to files.
+    fn write(&mut self, req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        info!("write {} {} {}", ino, offset, data.len());
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Write, &requester, ino, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support writing to extended attributes.
+    fn setxattr(&mut self, req: &Request, ino: u64, name: &OsStr, value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+        info!("setxattr {} {:?} {:?}", ino, name, value);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Setxattr, &requester, ino, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support removing extended attributes.
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("removexattr {} {:?}", ino, name);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Removexattr, &requester, ino, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support creating files.
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, _flags: u32, reply: ReplyCreate) {
+        info!("create {} {:?}", parent, name);
+        let (requester, name) = (req.into(), name.to_os_string());
+        self.spawn(move |fs| fs.apply_to_path(Operation::Create, &requester, parent, &name, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+    /// For this deco filesystem, we do not support file locks.
+    fn setlk(&mut self, req: &Request, ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
+        info!("setlk {}", ino);
+        let requester = req.into();
+        self.spawn(move |fs| fs.apply_to_ino(Operation::Setlk, &requester, ino, reply, |_path, reply| reply.fuse_error(EOPNOTSUPP)));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::EPERM;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use crate::builder::DecoFsBuilder;
+    use crate::config::Settings;
+    use crate::policy::{DefaultPolicy, SettingsPolicy};
+
+    const REQUESTER: Requester = Requester { uid: 0, gid: 0, pid: 1 };
+
+    fn decofs(settings: Settings) -> DecoFS {
+        let settings = Arc::new(RwLock::new(settings));
+        DecoFS::new(vec![PathBuf::from("t")], Precedence::First, settings.clone(), Box::new(SettingsPolicy::new(settings)), Vec::new(), 0, None)
+    }
+
+    #[test]
+    fn ino_to_path_root() {
+        let fs = decofs(Settings::default());
+        match fs.inner.ino_to_path(1) {
+            Ok(path) => assert_eq!(path, PathBuf::from("t")),
+            _ => panic!("root inode not found")
+        };
+    }
+
+    #[test]
+    fn ino_to_path_missing() {
+        let fs = decofs(Settings::default());
+        assert_eq!(fs.inner.ino_to_path(2), Err(ENOENT));
+    }
+
+    #[test]
+    fn apply_to_ino_root() {
+        struct TestReply { }
+        impl FuseError for TestReply {
+            fn fuse_error<E: Into<DecoError>>(self, error: E) {
+                let code = error.into().errno();
+                panic!("unexpected error {}", code);
+            }
+        }
+
+        let reply: TestReply = TestReply {};
+        let fs = decofs(Settings::default());
+        fs.inner.apply_to_ino(Operation::Getattr, &REQUESTER, 1, reply, |_path, _reply| ());
+    }
+
+    #[test]
+    fn apply_to_ino_missing() {
+        struct TestReply { }
+        impl FuseError for TestReply {
+            fn fuse_error<E: Into<DecoError>>(self, error: E) {
+                let code = error.into().errno();
+                assert_eq!(code, ENOENT);
+            }
+        }
+
+        let reply: TestReply = TestReply {};
+        let fs = decofs(Settings::default());
+        fs.inner.apply_to_ino(Operation::Getattr, &REQUESTER, 2, reply, |path, _reply| panic!("unexpected path {:?}", path));
+    }
+
+    #[test]
+    fn apply_to_path_denied() {
+        struct TestReply { }
+        impl FuseError for TestReply {
+            fn fuse_error<E: Into<DecoError>>(self, error: E) {
+                let code = error.into().errno();
+                assert_eq!(code, EPERM);
+            }
+        }
+
+        let mut settings = Settings::default();
+        settings.allow.unlink = false;
+        let fs = decofs(settings);
+        fs.inner.apply_to_path(Operation::Unlink, &REQUESTER, 1, OsStr::new("file"), TestReply {}, |path, _reply| panic!("unexpected path {:?}", path));
+    }
+
+    #[test]
+    fn panics_fail_one_request() {
+        let fs = decofs(Settings::default());
+        let served = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        fs.spawn(|_| panic!("bad entry"));
+        let flag = served.clone();
+        fs.spawn(move |fs| flag.store(fs.ino_to_path(1).is_ok(), Ordering::SeqCst));
+        assert!(served.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panics_holding_the_inode_table() {
+        let fs = decofs(Settings::default());
+        fs.spawn(|fs| {
+            let _inodes = fs.inodes_mut();
+            panic!("bad entry");
+        });
+        assert!(fs.inner.inodes.is_poisoned());
+        fs.inner.forget(inodes::ROOT, 1);
+        assert_eq!(fs.inner.ino_to_path(inodes::ROOT), Ok(PathBuf::from("t")));
+        assert_eq!(fs.inner.ino_to_path(2), Err(ENOENT));
+    }
+
+    #[test]
+    fn custom_policy() {
+        #[derive(Debug)]
+        struct DenyUser;
+        impl OperationPolicy for DenyUser {
+            fn check(&self, operation: Operation, path: &Path, attributes: Option<&Attributes>, requester: &Requester) -> Decision {
+                match requester.uid {
+                    0 => DefaultPolicy.check(operation, path, attributes, requester),
+                    _ => Decision::Deny(libc::EACCES)
+                }
+            }
+        }
+        struct TestReply { }
+        impl FuseError for TestReply {
+            fn fuse_error<E: Into<DecoError>>(self, error: E) {
+                let code = error.into().errno();
+                assert_eq!(code, libc::EACCES);
+            }
+        }
+
+        let fs = DecoFS::new(vec![PathBuf::from("t")], Precedence::First, Default::default(), Box::new(DenyUser), Vec::new(), 0, None);
+        let user = Requester { uid: 1000, gid: 1000, pid: 2 };
+        fs.inner.apply_to_ino(Operation::Read, &REQUESTER, 1, TestReply {}, |_path, _reply| ());
+        fs.inner.apply_to_ino(Operation::Read, &user, 1, TestReply {}, |path, _reply| panic!("unexpected path {:?}", path));
+    }
+
+    #[test]
+    fn stat_file_types() {
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink("/", dir.path().join("link")).unwrap();
+        let fs = DecoFsBuilder::new(dir.path()).build();
+        assert_eq!(fs.inner.stat(&dir.path().join("link")).unwrap().1.kind, FileType::Symlink);
+        assert_eq!(fs.inner.stat(dir.path()).unwrap().1.kind, FileType::Directory);
+        // Only files within the sources are examined.
+        assert_eq!(fs.inner.stat(Path::new("/dev/null")).unwrap_err().raw_os_error(), Some(libc::EXDEV));
+    }
+
+    #[test]
+    fn builder_ttl() {
+        let fs = DecoFsBuilder::new("t").ttl(Duration::from_millis(1500)).build();
+        assert_eq!(fs.inner.ttl(), Timespec { sec: 1, nsec: 500_000_000 });
+    }
+
+    #[test]
+    fn notifies_hooks() {
+        type Completed = (Operation, PathBuf, Result<(), c_int>);
+        #[derive(Debug, Default)]
+        struct Recorder {
+            completed: Arc<Mutex<Vec<Completed>>>
+        }
+        impl Hook for Recorder {
+            fn completed(&self, operation: Operation, _requester: &Requester, path: &Path, result: Result<(), c_int>) {
+                self.completed.lock().unwrap().push((operation, path.to_path_buf(), result));
+            }
+        }
+
+        let recorder = Recorder::default();
+        let completed = recorder.completed.clone();
+        let fs = DecoFsBuilder::new("t").hook(recorder).build();
+        fs.inner.notify(Operation::Unlink, &REQUESTER, Path::new("t/file"), &Ok::<(), io::Error>(()));
+        fs.inner.notify(Operation::Rmdir, &REQUESTER, Path::new("t/dir"), &Err::<(), io::Error>(io::Error::from_raw_os_error(libc::ENOTEMPTY)));
+        assert_eq!(*completed.lock().unwrap(), vec![
+            (Operation::Unlink, PathBuf::from("t/file"), Ok(())),
+            (Operation::Rmdir, PathBuf::from("t/dir"), Err(libc::ENOTEMPTY)),
+        ]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Include and exclude rules deciding which paths are exposed through the mount.
+//!
+//! Rules are globs matched against paths relative to the root of the mount, e.g. `/lost+found`,
+//! `**/.Trash-*` or `/home/**`; a leading `/` is optional, `*` does not match `/`, and `**`
+//! matches any number of directories. Names which are not valid UTF-8 are matched with each
+//! invalid sequence replaced by U+FFFD, so are matched by `*` and `?`.
+use std::path::{Component, Path};
+
+use glob::{MatchOptions, Pattern};
+
+const OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Paths hidden from the mount: those matching (or within a directory matching) an `exclude`
+/// rule, and, when there are `include` rules, those neither matching nor within a match.
+///
+/// Directories on the way to an included path remain visible, so that it can be reached.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    pattern: Pattern,
+    components: Vec<Pattern>,
+}
+
+impl Rule {
+    fn new(glob: &str) -> Result<Rule, String> {
+        let glob = glob.trim_start_matches('/');
+        let compile = |glob: &str| Pattern::new(glob).map_err(|e| format!("invalid glob '{}': {}", glob, e));
+        Ok(Rule {
+            pattern: compile(glob)?,
+            components: glob.split('/').filter(|c| !c.is_empty()).map(compile).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether `path`, or a directory containing it, matches.
+    fn covers(&self, path: &Path) -> bool {
+        path.ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| self.pattern.matches_with(&ancestor.to_string_lossy(), OPTIONS))
+    }
+
+    /// Whether something within the directory `path` could match.
+    fn leads_to(&self, path: &Path) -> bool {
+        let mut components = self.components.iter();
+        for name in path.components().filter_map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        }) {
+            match components.next() {
+                Some(pattern) if pattern.as_str() == "**" => return true,
+                Some(pattern) if pattern.matches_with(&name.to_string_lossy(), OPTIONS) => (),
+                _ => return false,
+            }
+        }
+        components.next().is_some()
+    }
+}
+
+impl Filter {
+    /// Filter from lists of `include` and `exclude` globs.
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<Filter, String> {
+        let rules = |globs: &[S]| globs.iter().map(|glob| Rule::new(glob.as_ref())).collect::<Result<Vec<_>, _>>();
+        Ok(Filter { include: rules(include)?, exclude: rules(exclude)? })
+    }
+
+    /// Whether `path`, relative to the root of the mount, is exposed.
+    pub fn is_visible(&self, path: &Path) -> bool {
+        if path.as_os_str().is_empty() {
+            return true;
+        }
+        if self.exclude.iter().any(|rule| rule.covers(path)) {
+            return false;
+        }
+        self.include.is_empty()
+            || self.include.iter().any(|rule| rule.covers(path) || rule.leads_to(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_rules() {
+        let filter = Filter::default();
+        assert!(filter.is_visible(Path::new("")));
+        assert!(filter.is_visible(Path::new("any/path")));
+    }
+
+    #[test]
+    fn exclude() {
+        let filter = Filter::new(&[] as &[&str], &["/lost+found", "**/.Trash-*"]).unwrap();
+        assert!(!filter.is_visible(Path::new("lost+found")));
+        assert!(!filter.is_visible(Path::new("lost+found/#123")));
+        assert!(filter.is_visible(Path::new("home/lost+found")));
+        assert!(!filter.is_visible(Path::new(".Trash-1000")));
+        assert!(!filter.is_visible(Path::new("home/user/.Trash-1000/file")));
+        assert!(filter.is_visible(Path::new("home/user/file")));
+    }
+
+    #[test]
+    fn include() {
+        let filter = Filter::new(&["/home/*/docs/**", "/etc/passwd"], &["**/*.tmp"]).unwrap();
+        assert!(filter.is_visible(Path::new("home")));
+        assert!(filter.is_visible(Path::new("home/user")));
+        assert!(filter.is_visible(Path::new("home/user/docs")));
+        assert!(filter.is_visible(Path::new("home/user/docs/a/b")));
+        assert!(!filter.is_visible(Path::new("home/user/music")));
+        assert!(!filter.is_visible(Path::new("home/user/docs/a.tmp")));
+        assert!(filter.is_visible(Path::new("etc/passwd")));
+        assert!(!filter.is_visible(Path::new("etc/shadow")));
+        assert!(!filter.is_visible(Path::new("var")));
+    }
+
+    #[test]
+    fn non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let filter = Filter::new(&["/home/*/docs/**"], &["**/*.tmp"]).unwrap();
+        assert!(filter.is_visible(&Path::new("home").join(OsStr::from_bytes(b"caf\xe9")).join("docs")));
+        assert!(!filter.is_visible(&Path::new("home/user/docs").join(OsStr::from_bytes(b"\xe9.tmp"))));
+    }
+
+    #[test]
+    fn invalid_glob() {
+        assert!(Filter::new(&["[a"], &[]).is_err());
+    }
+}
@@ -0,0 +1,15 @@
+//! Hooks notified of changes made to the source tree through the mount.
+use std::fmt::Debug;
+use std::path::Path;
+
+use libc::c_int;
+
+use crate::policy::{Operation, Requester};
+
+/// Notified once an operation which modifies the source tree (`unlink`, `rmdir`) has been
+/// attempted, e.g. to keep an audit trail of deletions.
+pub trait Hook: Debug + Send + Sync {
+    /// Called after `operation` was applied to `path` on behalf of `requester`, with the errno on
+    /// failure. Operations denied by the policy are not attempted, and so are not reported.
+    fn completed(&self, operation: Operation, requester: &Requester, path: &Path, result: Result<(), c_int>);
+}
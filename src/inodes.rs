@@ -0,0 +1,477 @@
+//! Translation between files in the source tree and FUSE inode numbers.
+//!
+//! Source inode numbers are only unique within a filesystem, and a source tree may span several
+//! (bind mounts, nested partitions, or the branches of a union), so FUSE inodes are allocated per
+//! `(st_dev, st_ino)` pair instead of passing `st_ino` through.
+//!
+//! Each inode is retained while the kernel holds a reference to it: from each `lookup` which
+//! returns it, until `forget` releases that many.
+//!
+//! A file with several hard links is one inode, reached by several names. Every name it has been
+//! found at is kept, so that when one is unlinked the inode remains reachable through the others.
+//!
+//! Each retained inode also holds an `O_PATH` descriptor for its source file, so that it follows
+//! the file when it is renamed on the source, rather than the name it was found at.
+use std::{fs, io};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use libc::{O_NONBLOCK, ESTALE};
+
+use crate::stats::Stats;
+use crate::store::{InodeStore, Record};
+
+/// FUSE inode number of the root of the mount.
+pub(crate) const ROOT: u64 = 1;
+
+/// Number of the most recent reservations by `peek` which are kept; older ones are dropped, and
+/// those files allocated a new number if ever looked up.
+const MAX_RESERVED: usize = 65536;
+
+/// Identity of a file in the source tree: its `(st_dev, st_ino)`.
+pub(crate) type SourceId = (u64, u64);
+
+/// Reference to a file in the source tree which follows it across renames, without opening it
+/// for reading or keeping its parent directories busy.
+#[derive(Debug)]
+pub(crate) struct Handle(File);
+
+impl Handle {
+    /// Handle on `file`, opened with `O_PATH`, if it is still the file `id`.
+    pub(crate) fn new(file: File, id: SourceId) -> io::Result<Handle> {
+        let handle = Handle(file);
+        match handle.id()? == id {
+            true => Ok(handle),
+            false => Err(io::Error::from_raw_os_error(ESTALE))
+        }
+    }
+
+    pub(crate) fn id(&self) -> io::Result<SourceId> {
+        let attr = self.0.metadata()?;
+        Ok((attr.st_dev(), attr.st_ino()))
+    }
+
+    /// Generation number of the file, distinguishing it from files which previously had the same
+    /// inode number; 0 if the filesystem keeps none, or it is not a regular file or directory
+    /// (which are not opened, so as not to disturb devices).
+    pub(crate) fn generation(&self) -> u32 {
+        match self.0.metadata() {
+            Ok(ref attr) if attr.is_file() || attr.is_dir() => (),
+            _ => return 0
+        }
+        // `O_PATH` descriptors do not support ioctls, so the file is reopened through procfs.
+        let file = match fs::OpenOptions::new().read(true).custom_flags(O_NONBLOCK).open(format!("/proc/self/fd/{}", self.0.as_raw_fd())) {
+            Ok(file) => file,
+            Err(_) => return 0
+        };
+        let mut generation: libc::c_long = 0;
+        match unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETVERSION, &mut generation) } {
+            0 => generation as u32,
+            _ => 0
+        }
+    }
+
+    /// Current path of the file, as reported by the kernel, or `ESTALE` if the name it was found
+    /// at has been removed.
+    pub(crate) fn path(&self) -> io::Result<PathBuf> {
+        let path = fs::read_link(format!("/proc/self/fd/{}", self.0.as_raw_fd()))?;
+        // The kernel marks removed names with a suffix, which a file may also legitimately have.
+        if path.as_os_str().to_string_lossy().ends_with(" (deleted)") {
+            let found = fs::symlink_metadata(&path).map(|attr| (attr.st_dev(), attr.st_ino()));
+            if found.ok() != self.id().ok() {
+                return Err(io::Error::from_raw_os_error(ESTALE));
+            }
+        }
+        Ok(path)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Names the inode has been found at, least recently found first.
+    paths: Vec<PathBuf>,
+    id: Option<SourceId>,
+    handle: Option<Arc<Handle>>,
+    lookups: u64,
+}
+
+/// Inodes referenced by the kernel, each with the paths (relative to the root of the mount) it has
+/// been found at.
+#[derive(Debug)]
+pub(crate) struct InodeTable {
+    entries: HashMap<u64, Entry>,
+    inodes: HashMap<SourceId, u64>,
+    names: HashMap<PathBuf, u64>,
+    /// Numbers given by `peek` to files not yet retained, which they are allocated on `lookup`.
+    reserved: HashMap<SourceId, u64>,
+    /// Reservations made, oldest first, including those since allocated.
+    reservations: VecDeque<(SourceId, u64)>,
+    next: u64,
+    store: Option<InodeStore>,
+    stats: Arc<Stats>,
+}
+
+impl InodeTable {
+    /// Table containing the root of the mount, which is `root` in the source tree if known.
+    pub(crate) fn new(root: Option<SourceId>, stats: Arc<Stats>) -> InodeTable {
+        let mut entries = HashMap::new();
+        entries.insert(ROOT, Entry { paths: vec![PathBuf::new()], id: root, handle: None, lookups: 0 });
+        let inodes = root.into_iter().map(|id| (id, ROOT)).collect();
+        let names = vec![(PathBuf::new(), ROOT)].into_iter().collect();
+        let table = InodeTable { entries, inodes, names, reserved: HashMap::new(), reservations: VecDeque::new(), next: ROOT + 1, store: None, stats };
+        table.update_stats();
+        table
+    }
+
+    /// Allocate inode numbers as recorded in `store`, recording new ones there, so that each file
+    /// keeps its number from one mount to the next.
+    pub(crate) fn persist(&mut self, store: InodeStore) {
+        self.next = self.next.max(store.next());
+        self.store = Some(store);
+    }
+
+    fn update_stats(&self) {
+        self.stats.inodes.store(self.entries.len(), Ordering::Relaxed);
+        self.stats.reserved_inodes.store(self.reserved.len(), Ordering::Relaxed);
+    }
+
+    /// Number of inodes retained.
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Every path the inode `ino` is known by, most recently found first.
+    pub(crate) fn paths(&self, ino: u64) -> impl Iterator<Item = &Path> {
+        self.entries.get(&ino).into_iter().flat_map(|entry| entry.paths.iter().rev().map(PathBuf::as_path))
+    }
+
+    /// Handle on the source file of the inode `ino`, if retained and one could be opened.
+    ///
+    /// The root has none: it is always the root of the branches.
+    pub(crate) fn handle(&self, ino: u64) -> Option<Arc<Handle>> {
+        self.entries.get(&ino).and_then(|entry| entry.handle.clone())
+    }
+
+    /// Inode most recently found at `path`, if retained.
+    pub(crate) fn ino(&self, path: &Path) -> Option<u64> {
+        self.names.get(path).copied()
+    }
+
+    /// Whether the source file `id` has an inode retained.
+    pub(crate) fn retains(&self, id: SourceId) -> bool {
+        self.inodes.contains_key(&id)
+    }
+
+    /// Whether the store records a number for the source file `id`, which has no inode retained;
+    /// `peek` only gives it once the file's generation is known.
+    pub(crate) fn recorded(&self, id: SourceId) -> bool {
+        !self.inodes.contains_key(&id) && self.store.as_ref().is_some_and(|store| store.get(id).is_some())
+    }
+
+    /// Identity in the source tree of the inode `ino`, if retained and known.
+    pub(crate) fn id(&self, ino: u64) -> Option<SourceId> {
+        self.entries.get(&ino).and_then(|entry| entry.id)
+    }
+
+    /// Inode for the source file `id`, of generation `generation` if known, without retaining it:
+    /// the inode already allocated, or that recorded in the store for the same generation, or
+    /// else a number reserved for it, which a later `lookup` allocates.
+    ///
+    /// For `readdir`, whose entries are not referenced by the kernel, but must have the numbers
+    /// `lookup` gives them. Only the most recent `MAX_RESERVED` reservations are kept, so that
+    /// listing a large tree which is never looked up does not grow the table without limit.
+    pub(crate) fn peek(&mut self, id: SourceId, generation: Option<u32>) -> u64 {
+        if let Some(ino) = self.inodes.get(&id).or_else(|| self.reserved.get(&id)) {
+            return *ino;
+        }
+        match self.store.as_ref().and_then(|store| store.get(id)) {
+            Some(record) if Some(record.generation) == generation => return record.ino,
+            _ => ()
+        }
+        let ino = self.next;
+        self.next += 1;
+        self.reserved.insert(id, ino);
+        self.reservations.push_back((id, ino));
+        while self.reservations.len() > MAX_RESERVED {
+            let (id, ino) = self.reservations.pop_front().unwrap();
+            if self.reserved.get(&id) == Some(&ino) {
+                self.reserved.remove(&id);
+            }
+        }
+        self.update_stats();
+        ino
+    }
+
+    /// Inode for the source file `id`, found at `path`, allocating one if needed, and counting a
+    /// reference from the kernel. `open` is called for a handle on the file when allocating.
+    pub(crate) fn lookup<F>(&mut self, id: SourceId, path: PathBuf, open: F) -> u64 where F: FnOnce() -> Option<Handle> {
+        if let Some(&ino) = self.inodes.get(&id) {
+            self.entries.get_mut(&ino).unwrap().lookups += 1;
+            // The root is always at the root of the mount, however else it is reached.
+            if ino != ROOT {
+                self.name(ino, path);
+            }
+            return ino;
+        }
+        let handle = open().map(Arc::new);
+        let ino = self.allocate(id, handle.as_deref());
+        self.inodes.insert(id, ino);
+        self.entries.insert(ino, Entry { paths: Vec::new(), id: Some(id), handle, lookups: 1 });
+        self.name(ino, path);
+        self.update_stats();
+        ino
+    }
+
+    /// Inode number for the source file `id`: any reserved by `peek` (which only reserves one
+    /// when the store's record could not be verified), or else that recorded in the store, if it
+    /// is the same file (by generation), or else the next free number.
+    fn allocate(&mut self, id: SourceId, handle: Option<&Handle>) -> u64 {
+        let reserved = self.reserved.remove(&id);
+        let generation = handle.map(Handle::generation).unwrap_or(0);
+        match self.store.as_ref().and_then(|store| store.get(id)) {
+            Some(record) if record.generation == generation && reserved.is_none() => return record.ino,
+            _ => ()
+        }
+        let ino = reserved.unwrap_or_else(|| {
+            self.next += 1;
+            self.next - 1
+        });
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return ino
+        };
+        let record = Record { generation, ino };
+        if let Err(e) = store.insert(id, record) {
+            warn!("failed to record inode {}: {}", ino, e);
+        }
+        ino
+    }
+
+    /// Record `path` as the most recently found name of `ino`, taking it from any other inode it
+    /// named (the file having since been replaced).
+    fn name(&mut self, ino: u64, path: PathBuf) {
+        match self.names.insert(path.clone(), ino) {
+            Some(previous) if previous != ino => self.drop_name(previous, &path),
+            _ => ()
+        }
+        let paths = &mut self.entries.get_mut(&ino).unwrap().paths;
+        paths.retain(|known| *known != path);
+        paths.push(path);
+    }
+
+    fn drop_name(&mut self, ino: u64, path: &Path) {
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            entry.paths.retain(|known| known != path);
+        }
+    }
+
+    /// Forget the name `path`, which has been removed, returning the inode it named. The inode
+    /// remains reachable through any other name, or by number while the kernel references it.
+    pub(crate) fn unlink(&mut self, path: &Path) -> Option<u64> {
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+        let ino = self.names.remove(path)?;
+        self.drop_name(ino, path);
+        Some(ino)
+    }
+
+    /// Release `nlookup` references to `ino`, evicting it once none remain.
+    pub(crate) fn forget(&mut self, ino: u64, nlookup: u64) {
+        let entry = match self.entries.get_mut(&ino) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.lookups = entry.lookups.saturating_sub(nlookup);
+        if entry.lookups > 0 || ino == ROOT {
+            return;
+        }
+        let entry = self.entries.remove(&ino).unwrap();
+        if let Some(id) = entry.id {
+            self.inodes.remove(&id);
+        }
+        for path in entry.paths {
+            self.names.remove(&path);
+        }
+        self.update_stats();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{O_NOFOLLOW, O_PATH};
+
+    /// `O_PATH` descriptor for `path`, or for the link if it is one.
+    fn open(path: &Path) -> File {
+        fs::OpenOptions::new().read(true).custom_flags(O_PATH | O_NOFOLLOW).open(path).unwrap()
+    }
+
+    #[test]
+    fn root() {
+        let mut table = InodeTable::new(Some((1, 2)), Default::default());
+        assert_eq!(table.paths(ROOT).next(), Some(Path::new("")));
+        assert_eq!(table.lookup((1, 2), PathBuf::from("loop"), || None), ROOT);
+        assert_eq!(table.paths(ROOT).next(), Some(Path::new("")));
+        table.forget(ROOT, 1);
+        assert_eq!(table.paths(ROOT).next(), Some(Path::new("")));
+    }
+
+    #[test]
+    fn same_inode_on_different_devices() {
+        let mut table = InodeTable::new(None, Default::default());
+        let a = table.lookup((1, 100), PathBuf::from("a"), || None);
+        let b = table.lookup((2, 100), PathBuf::from("b"), || None);
+        assert_ne!(a, b);
+        assert_ne!(a, ROOT);
+        assert_eq!(table.lookup((1, 100), PathBuf::from("a"), || None), a);
+        assert_eq!(table.paths(a).next(), Some(Path::new("a")));
+        assert_eq!(table.paths(b).next(), Some(Path::new("b")));
+        assert_eq!(table.paths(b + 1).next(), None);
+        assert_eq!(table.ino(Path::new("b")), Some(b));
+        assert_eq!(table.ino(Path::new("c")), None);
+    }
+
+    #[test]
+    fn forget_evicts() {
+        let stats = Arc::new(Stats::default());
+        let mut table = InodeTable::new(None, stats.clone());
+        let a = table.lookup((1, 100), PathBuf::from("a"), || None);
+        assert_eq!(table.lookup((1, 100), PathBuf::from("a"), || None), a);
+        assert_eq!(stats.inodes(), 2);
+        table.forget(a, 1);
+        assert_eq!(table.paths(a).next(), Some(Path::new("a")));
+        table.forget(a, 1);
+        assert_eq!(table.paths(a).next(), None);
+        assert_eq!(table.len(), 1);
+        assert_eq!(stats.inodes(), 1);
+        assert_ne!(table.lookup((1, 100), PathBuf::from("a"), || None), a);
+    }
+
+    #[test]
+    fn peek_reserves() {
+        let stats = Arc::new(Stats::default());
+        let mut table = InodeTable::new(None, stats.clone());
+        let a = table.lookup((1, 100), PathBuf::from("a"), || None);
+        assert_eq!(table.peek((1, 100), None), a);
+        let b = table.peek((1, 101), None);
+        assert_ne!(b, a);
+        assert_eq!(table.peek((1, 101), None), b);
+        assert_eq!(table.paths(b).next(), None);
+        assert_eq!(table.len(), 2);
+        assert_eq!(stats.reserved_inodes(), 1);
+
+        let c = table.peek((1, 102), None);
+        assert_eq!(table.lookup((1, 102), PathBuf::from("c"), || None), c);
+        assert_eq!(table.lookup((1, 101), PathBuf::from("b"), || None), b);
+        assert_eq!(table.lookup((1, 103), PathBuf::from("d"), || None), c + 1);
+        assert_eq!(table.len(), 5);
+        assert_eq!(stats.reserved_inodes(), 0);
+    }
+
+    #[test]
+    fn reservations_are_limited() {
+        let stats = Arc::new(Stats::default());
+        let mut table = InodeTable::new(None, stats.clone());
+        let first = table.peek((1, 0), None);
+        for i in 1..=MAX_RESERVED as u64 {
+            table.peek((1, i), None);
+        }
+        assert_eq!(stats.reserved_inodes(), MAX_RESERVED);
+        assert_ne!(table.lookup((1, 0), PathBuf::from("first"), || None), first);
+        assert_eq!(table.lookup((1, 1), PathBuf::from("second"), || None), first + 1);
+    }
+
+    #[test]
+    fn hard_links() {
+        let mut table = InodeTable::new(None, Default::default());
+        let a = table.lookup((1, 100), PathBuf::from("a"), || None);
+        assert_eq!(table.lookup((1, 100), PathBuf::from("dir/b"), || None), a);
+        assert_eq!(table.paths(a).next(), Some(Path::new("dir/b")));
+        assert_eq!(table.paths(a).collect::<Vec<_>>(), vec![Path::new("dir/b"), Path::new("a")]);
+        assert_eq!(table.unlink(Path::new("dir/b")), Some(a));
+        assert_eq!(table.paths(a).next(), Some(Path::new("a")));
+        assert_eq!(table.unlink(Path::new("dir/b")), None);
+        assert_eq!(table.ino(Path::new("dir/b")), None);
+        assert_eq!(table.unlink(Path::new("a")), Some(a));
+        assert_eq!(table.paths(a).next(), None);
+        assert_eq!(table.id(a), Some((1, 100)));
+    }
+
+    #[test]
+    fn handle_follows_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "").unwrap();
+        let attr = fs::metadata(&a).unwrap();
+        let id = (attr.st_dev(), attr.st_ino());
+        assert_eq!(Handle::new(open(&a), (id.0, id.1 + 1)).unwrap_err().raw_os_error(), Some(ESTALE));
+        let handle = Handle::new(open(&a), id).unwrap();
+        fs::rename(&a, &b).unwrap();
+        assert_eq!(handle.path().unwrap(), fs::canonicalize(&b).unwrap());
+        fs::remove_file(&b).unwrap();
+        assert_eq!(handle.path().unwrap_err().raw_os_error(), Some(ESTALE));
+
+        let mut table = InodeTable::new(None, Default::default());
+        let ino = table.lookup(id, PathBuf::from("a"), || Some(handle));
+        assert!(table.handle(ino).is_some());
+        assert_eq!(table.lookup(id, PathBuf::from("b"), || panic!("already open")), ino);
+    }
+
+    #[test]
+    fn handle_on_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("missing", &link).unwrap();
+        let attr = fs::symlink_metadata(&link).unwrap();
+        let handle = Handle::new(open(&link), (attr.st_dev(), attr.st_ino())).unwrap();
+        assert_eq!(handle.path().unwrap(), fs::canonicalize(dir.path()).unwrap().join("link"));
+    }
+
+    #[test]
+    fn persistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("inodes");
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let attr = fs::metadata(&file).unwrap();
+        let id = (attr.st_dev(), attr.st_ino());
+
+        let mut table = InodeTable::new(None, Default::default());
+        table.persist(InodeStore::open(&store).unwrap());
+        let a = table.lookup((1, 100), PathBuf::from("a"), || None);
+        let ino = table.lookup(id, PathBuf::from("file"), || Handle::new(open(&file), id).ok());
+
+        let mut table = InodeTable::new(None, Default::default());
+        table.persist(InodeStore::open(&store).unwrap());
+        let generation = Handle::new(open(&file), id).unwrap().generation();
+        assert!(table.recorded(id));
+        assert_eq!(table.peek(id, Some(generation)), ino);
+        // Recorded with generation 0, so another file may since have its identity.
+        let reused = table.peek((1, 100), Some(7));
+        assert_ne!(reused, a);
+        assert_eq!(table.lookup((1, 100), PathBuf::from("a"), || None), reused);
+        assert!(!table.recorded((1, 100)));
+        let b = table.lookup((1, 101), PathBuf::from("b"), || None);
+        assert!(b > ino);
+        assert_eq!(table.lookup(id, PathBuf::from("file"), || Handle::new(open(&file), id).ok()), ino);
+    }
+
+    #[test]
+    fn replaced_name() {
+        let mut table = InodeTable::new(None, Default::default());
+        let a = table.lookup((1, 100), PathBuf::from("a"), || None);
+        let b = table.lookup((1, 101), PathBuf::from("a"), || None);
+        assert_ne!(a, b);
+        assert_eq!(table.paths(a).next(), None);
+        assert_eq!(table.paths(b).next(), Some(Path::new("a")));
+        table.forget(a, 1);
+        assert_eq!(table.unlink(Path::new("a")), Some(b));
+    }
+}
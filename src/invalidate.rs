@@ -0,0 +1,76 @@
+//! Invalidation of entries and attributes the kernel has cached, once the source has changed.
+//!
+//! fuse has no means of sending notifications, so they are written to a descriptor of our own on
+//! the session's connection: a notification is a reply with no request (`unique` 0) and the kind
+//! of notification in place of an error.
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+
+use libc::{c_int, ENOENT, F_DUPFD_CLOEXEC};
+
+const FUSE_NOTIFY_INVAL_INODE: i32 = 2;
+const FUSE_NOTIFY_INVAL_ENTRY: i32 = 3;
+
+/// Sends invalidations to the kernel, on a connection to `/dev/fuse`.
+#[derive(Debug)]
+pub(crate) struct Invalidator(File);
+
+/// Descriptors open on `/dev/fuse`, from which those of a new mount can be told apart.
+pub(crate) fn connections() -> Vec<RawFd> {
+    fs::read_dir("/proc/self/fd").into_iter().flatten().flatten()
+        .filter(|entry| fs::read_link(entry.path()).map(|target| target == Path::new("/dev/fuse")).unwrap_or(false))
+        .filter_map(|entry| entry.file_name().to_str().and_then(|fd| fd.parse().ok()))
+        .collect()
+}
+
+impl Invalidator {
+    /// Invalidator on the connection `fd`, which is duplicated, so that it stays open for as long
+    /// as the invalidator does.
+    pub(crate) fn new(fd: RawFd) -> io::Result<Invalidator> {
+        match unsafe { libc::fcntl(fd, F_DUPFD_CLOEXEC, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Invalidator(unsafe { File::from_raw_fd(fd as c_int) }))
+        }
+    }
+
+    fn send(&self, code: i32, arguments: &[u8]) -> io::Result<()> {
+        let len = (16 + arguments.len()) as u32;
+        let mut message = Vec::with_capacity(len as usize);
+        message.extend_from_slice(&len.to_ne_bytes());
+        message.extend_from_slice(&code.to_ne_bytes());
+        message.extend_from_slice(&0u64.to_ne_bytes());
+        message.extend_from_slice(arguments);
+        match (&self.0).write(&message) {
+            // Nothing was cached to invalidate.
+            Err(ref e) if e.raw_os_error() == Some(ENOENT) => Ok(()),
+            result => result.map(|_| ())
+        }
+    }
+
+    /// Drop the attributes and contents cached for the inode `ino`.
+    pub(crate) fn inode(&self, ino: u64) -> io::Result<()> {
+        let mut arguments = Vec::with_capacity(3 * size_of::<u64>());
+        arguments.extend_from_slice(&ino.to_ne_bytes());
+        // From offset 0, for a length of 0: all of it.
+        arguments.extend_from_slice(&0i64.to_ne_bytes());
+        arguments.extend_from_slice(&0i64.to_ne_bytes());
+        self.send(FUSE_NOTIFY_INVAL_INODE, &arguments)
+    }
+
+    /// Drop the entry cached for `name` in the directory `parent`, so that it is looked up again.
+    pub(crate) fn entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        let name = name.as_bytes();
+        let mut arguments = Vec::with_capacity(16 + name.len() + 1);
+        arguments.extend_from_slice(&parent.to_ne_bytes());
+        arguments.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+        arguments.extend_from_slice(&0u32.to_ne_bytes());
+        arguments.extend_from_slice(name);
+        arguments.push(0);
+        self.send(FUSE_NOTIFY_INVAL_ENTRY, &arguments)
+    }
+}
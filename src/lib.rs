@@ -0,0 +1,49 @@
+//! Implementation of a pass-through filesystem, to assist with disk decommissioning.
+//!
+//! Supports file deletion, and read operations, but no creation or renaming. Several source
+//! directories may be presented as a single merged tree.
+//!
+//! A `DecoFS` is constructed with a `DecoFsBuilder`, and mounted with `DecoFS::mount`, or on a
+//! background thread with `DecoFS::spawn_mount`.
+#![warn(missing_docs, bad_style, unused, unused_extern_crates, unused_import_braces, unused_qualifications, missing_debug_implementations, unused_variables)]
+#[macro_use]
+extern crate log;
+
+mod attributes;
+mod beneath;
+mod builder;
+pub mod config;
+pub mod daemon;
+mod error;
+mod filesystem;
+mod filter;
+mod hook;
+mod inodes;
+mod invalidate;
+pub mod options;
+mod open_files;
+mod ownership;
+pub mod policy;
+mod service;
+mod session;
+mod stats;
+mod store;
+mod symlinks;
+mod union;
+mod watcher;
+mod workers;
+
+pub use attributes::Attributes;
+pub use builder::DecoFsBuilder;
+pub use config::{Config, Settings};
+pub use filesystem::DecoFS;
+pub use filter::Filter;
+pub use hook::Hook;
+pub use options::MountOptions;
+pub use ownership::{IdMap, Ownership};
+pub use policy::{Decision, DefaultPolicy, Operation, OperationPolicy, Requester, SettingsPolicy};
+pub use service::Service;
+pub use session::{unmount, MountHandle, Session};
+pub use stats::Stats;
+pub use symlinks::Symlinks;
+pub use union::Precedence;
@@ -0,0 +1,125 @@
+//! Files opened through the mount, by the file handle (`fh`) given to the kernel for each, so
+//! that reads need not open the file again, and continue once it is renamed or removed.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::attributes::Attributes;
+use crate::stats::Stats;
+
+/// Source file opened through the mount.
+#[derive(Debug)]
+pub(crate) struct OpenFile {
+    file: File,
+    /// Inode it was opened by.
+    pub(crate) ino: u64,
+    /// Source path at which it was opened, which the policy is consulted for.
+    pub(crate) path: PathBuf,
+}
+
+impl OpenFile {
+    /// Up to `size` bytes from `offset`, fewer only at the end of the file.
+    pub(crate) fn read(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0; size];
+        let mut read = 0;
+        while read < size {
+            match self.file.read_at(&mut buffer[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(count) => read += count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    /// Attributes of the file, even once removed from the source.
+    pub(crate) fn attributes(&self) -> io::Result<Attributes> {
+        Attributes::of_file(&self.file)
+    }
+}
+
+/// Open files, by file handle.
+#[derive(Debug)]
+pub(crate) struct OpenFiles {
+    files: RwLock<HashMap<u64, Arc<OpenFile>>>,
+    next: AtomicU64,
+    stats: Arc<Stats>,
+}
+
+impl OpenFiles {
+    pub(crate) fn new(stats: Arc<Stats>) -> OpenFiles {
+        OpenFiles { files: RwLock::new(HashMap::new()), next: AtomicU64::new(1), stats }
+    }
+
+    /// The files, even if a request panicked while holding them, as for the inode table.
+    fn files(&self) -> RwLockReadGuard<'_, HashMap<u64, Arc<OpenFile>>> {
+        self.files.read().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn files_mut(&self) -> RwLockWriteGuard<'_, HashMap<u64, Arc<OpenFile>>> {
+        self.files.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Keep `file`, opened by inode `ino` at `path`, returning its file handle.
+    pub(crate) fn insert(&self, ino: u64, file: File, path: PathBuf) -> u64 {
+        let fh = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut files = self.files_mut();
+        files.insert(fh, Arc::new(OpenFile { file, ino, path }));
+        self.stats.open_files.store(files.len(), Ordering::Relaxed);
+        fh
+    }
+
+    /// File with handle `fh`.
+    pub(crate) fn get(&self, fh: u64) -> Option<Arc<OpenFile>> {
+        self.files().get(&fh).cloned()
+    }
+
+    /// Any file open by inode `ino`.
+    pub(crate) fn of_inode(&self, ino: u64) -> Option<Arc<OpenFile>> {
+        self.files().values().find(|file| file.ino == ino).cloned()
+    }
+
+    /// Release the file with handle `fh`, which is closed once no request is reading it.
+    pub(crate) fn remove(&self, fh: u64) -> Option<Arc<OpenFile>> {
+        let mut files = self.files_mut();
+        let file = files.remove(&fh);
+        self.stats.open_files.store(files.len(), Ordering::Relaxed);
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn open_read_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "0123456789").unwrap();
+        let stats = Arc::new(Stats::default());
+        let files = OpenFiles::new(stats.clone());
+        let fh = files.insert(2, File::open(&path).unwrap(), path.clone());
+        assert_eq!(stats.open_files(), 1);
+
+        fs::remove_file(&path).unwrap();
+        let file = files.get(fh).unwrap();
+        assert_eq!(file.path, path);
+        assert_eq!(files.of_inode(2).unwrap().path, path);
+        assert!(files.of_inode(3).is_none());
+        assert_eq!(file.attributes().unwrap().nlink, 0);
+        assert_eq!(file.read(2, 3).unwrap(), b"234");
+        assert_eq!(file.read(8, 5).unwrap(), b"89");
+        assert_eq!(file.read(20, 5).unwrap(), b"");
+
+        assert!(files.remove(fh).is_some());
+        assert!(files.get(fh).is_none() && files.remove(fh).is_none());
+        assert_eq!(stats.open_files(), 0);
+    }
+}
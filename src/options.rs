@@ -0,0 +1,127 @@
+//! FUSE mount option handling.
+use std::ffi::OsString;
+
+/// Options which take no value.
+const FLAGS: &[&str] = &[
+    "rw", "ro", "allow_other", "allow_root", "auto_unmount", "default_permissions", "nonempty",
+    "suid", "nosuid", "dev", "nodev", "exec", "noexec", "sync", "async", "atime", "noatime", "dirsync",
+];
+
+/// Options which require a value.
+const VALUED: &[&str] = &["fsname", "subtype", "max_read", "blksize"];
+
+/// Options which cancel each other out; setting one removes the other.
+const OPPOSITES: &[(&str, &str)] = &[
+    ("rw", "ro"), ("allow_other", "allow_root"), ("suid", "nosuid"), ("dev", "nodev"),
+    ("exec", "noexec"), ("sync", "async"), ("atime", "noatime"),
+];
+
+/// Set of validated options, passed to `fuse::mount`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountOptions {
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Default for MountOptions {
+    fn default() -> MountOptions {
+        let mut options = MountOptions { options: Vec::new() };
+        for option in &["rw", "fsname=decofs", "allow_other"] {
+            options.set(option).unwrap();
+        }
+        options
+    }
+}
+
+impl MountOptions {
+    /// Set a single `key` or `key=value` option, replacing any previous value for the same key.
+    pub fn set(&mut self, option: &str) -> Result<(), String> {
+        let (key, value) = match option.find('=') {
+            Some(i) => (&option[..i], Some(&option[i + 1..])),
+            None => (option, None),
+        };
+        match value {
+            None if FLAGS.contains(&key) => (),
+            Some(_) if FLAGS.contains(&key) => return Err(format!("mount option '{}' does not take a value", key)),
+            Some(v) if VALUED.contains(&key) && !v.is_empty() => (),
+            _ if VALUED.contains(&key) => return Err(format!("mount option '{}' requires a value", key)),
+            _ => return Err(format!("unknown mount option '{}'", key)),
+        };
+        for (a, b) in OPPOSITES {
+            if key == *a {
+                self.remove(b);
+            } else if key == *b {
+                self.remove(a);
+            }
+        }
+        self.remove(key);
+        self.options.push((key.to_string(), value.map(str::to_string)));
+        Ok(())
+    }
+
+    /// Set each option from a comma-separated list.
+    pub fn set_all(&mut self, list: &str) -> Result<(), String> {
+        list.split(',')
+            .filter(|option| !option.is_empty())
+            .try_for_each(|option| self.set(option))
+    }
+
+    /// Remove an option, if present.
+    pub fn remove(&mut self, key: &str) {
+        self.options.retain(|(k, _)| k != key);
+    }
+
+    /// Whether the named option is set.
+    pub fn contains(&self, key: &str) -> bool {
+        self.options.iter().any(|(k, _)| k == key)
+    }
+
+    /// Arguments to pass to `fuse::mount`.
+    pub fn to_args(&self) -> Vec<OsString> {
+        self.options.iter()
+            .flat_map(|(key, value)| {
+                let option = match value {
+                    Some(value) => format!("{}={}", key, value),
+                    None => key.clone(),
+                };
+                vec![OsString::from("-o"), OsString::from(option)]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_options() {
+        let args = MountOptions::default().to_args();
+        assert_eq!(args, ["-o", "rw", "-o", "fsname=decofs", "-o", "allow_other"]);
+    }
+
+    #[test]
+    fn replaces_value() {
+        let mut options = MountOptions::default();
+        options.set_all("fsname=disk1,subtype=decofs").unwrap();
+        assert_eq!(options.to_args(), ["-o", "rw", "-o", "allow_other", "-o", "fsname=disk1", "-o", "subtype=decofs"]);
+    }
+
+    #[test]
+    fn replaces_opposite() {
+        let mut options = MountOptions::default();
+        options.set("ro").unwrap();
+        options.set("allow_root").unwrap();
+        assert!(!options.contains("rw"));
+        assert!(!options.contains("allow_other"));
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let mut options = MountOptions::default();
+        assert!(options.set("bogus").is_err());
+        assert!(options.set("fsname").is_err());
+        assert!(options.set("fsname=").is_err());
+        assert!(options.set("allow_other=1").is_err());
+        assert_eq!(options, MountOptions::default());
+    }
+}
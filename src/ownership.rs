@@ -0,0 +1,198 @@
+//! Ownership presented through the mount, for disks whose owners were users of another machine.
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use libc::O_RDONLY;
+
+use crate::beneath;
+
+/// Owners and groups presented in place of those on the source, by id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    /// Presented uid for each source uid.
+    pub users: HashMap<u32, u32>,
+    /// Presented gid for each source gid.
+    pub groups: HashMap<u32, u32>,
+}
+
+/// How the owner and group of source files are presented.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Ownership {
+    /// As on the source.
+    #[default]
+    Source,
+    /// Every file owned by the given uid and gid.
+    Squash(u32, u32),
+    /// Mapped by the table, with ids not in it presented as on the source.
+    Map(IdMap),
+    /// Mapped by name, from the users and groups in `/etc/passwd` and `/etc/group` on the source
+    /// to those of the same name on the host, with ids in the table mapped by it instead.
+    Passwd(IdMap),
+}
+
+/// Parse `SOURCE:PRESENTED`, a pair of ids.
+pub(crate) fn pair(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("invalid id pair '{}', expected ID:ID", value);
+    let colon = value.find(':').ok_or_else(invalid)?;
+    Ok((value[..colon].parse().map_err(|_| invalid())?, value[colon + 1..].parse().map_err(|_| invalid())?))
+}
+
+impl Ownership {
+    /// Owner and group presented for a source file owned by `uid` and `gid`.
+    pub(crate) fn present(&self, uid: u32, gid: u32, accounts: &IdMap) -> (u32, u32) {
+        let lookup = |table: &HashMap<u32, u32>, id: u32| table.get(&id).copied();
+        match self {
+            Ownership::Source => (uid, gid),
+            Ownership::Squash(uid, gid) => (*uid, *gid),
+            Ownership::Map(map) => (lookup(&map.users, uid).unwrap_or(uid), lookup(&map.groups, gid).unwrap_or(gid)),
+            Ownership::Passwd(map) => (
+                lookup(&map.users, uid).or_else(|| lookup(&accounts.users, uid)).unwrap_or(uid),
+                lookup(&map.groups, gid).or_else(|| lookup(&accounts.groups, gid)).unwrap_or(gid),
+            ),
+        }
+    }
+}
+
+/// Contents of `path` within the system whose root is `source`, opened without following
+/// symbolic links, so that an `etc/passwd` linking to the host's cannot be read in its place.
+fn read_beneath(source: &Path, path: &Path) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    beneath::open(source, path, O_RDONLY, false)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Ids by name, from the contents of a file in the format of `/etc/passwd` or `/etc/group`; lines
+/// which are not valid entries are skipped, and a missing file has none.
+fn ids(contents: io::Result<Vec<u8>>) -> io::Result<HashMap<String, u32>> {
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e)
+    };
+    Ok(String::from_utf8_lossy(&contents).lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next().filter(|name| !name.is_empty())?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((name.to_string(), id))
+        })
+        .collect())
+}
+
+/// Map from the ids in the file `path` of the system whose root is `source`, to those with the
+/// same name in the same file of the system whose root is `host`.
+fn by_name(source: &Path, host: &Path, path: &Path) -> io::Result<HashMap<u32, u32>> {
+    let host = ids(fs::read(host.join(path)))?;
+    Ok(ids(read_beneath(source, path))?.into_iter()
+        .filter_map(|(name, id)| host.get(&name).map(|host| (id, *host)))
+        .collect())
+}
+
+/// Map from the users and groups of the system whose root is `source`, to those of the same name
+/// in the system whose root is `host`.
+fn system_accounts(source: &Path, host: &Path) -> io::Result<IdMap> {
+    Ok(IdMap {
+        users: by_name(source, host, Path::new("etc/passwd"))?,
+        groups: by_name(source, host, Path::new("etc/group"))?,
+    })
+}
+
+/// Map from the users and groups of the systems whose roots are `sources` (e.g. the branches of a
+/// union), to those of the same name in the system whose root is `host`; an id which names
+/// different accounts in several is mapped as in the first. Sources which cannot be read are
+/// skipped.
+pub(crate) fn accounts(sources: &[PathBuf], host: &Path) -> IdMap {
+    let mut accounts = IdMap::default();
+    for source in sources {
+        match system_accounts(source, host) {
+            Ok(found) => {
+                for (id, presented) in found.users {
+                    accounts.users.entry(id).or_insert(presented);
+                }
+                for (id, presented) in found.groups {
+                    accounts.groups.entry(id).or_insert(presented);
+                }
+            },
+            Err(e) => warn!("failed to read accounts on {:?}: {}", source, e)
+        }
+    }
+    accounts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn present() {
+        let map = IdMap { users: vec![(1000, 2000)].into_iter().collect(), groups: vec![(100, 200)].into_iter().collect() };
+        let accounts = IdMap { users: vec![(1000, 3000), (1001, 3001)].into_iter().collect(), groups: HashMap::new() };
+        assert_eq!(Ownership::Source.present(1000, 100, &accounts), (1000, 100));
+        assert_eq!(Ownership::Squash(65534, 65534).present(1000, 100, &accounts), (65534, 65534));
+        assert_eq!(Ownership::Map(map.clone()).present(1000, 100, &accounts), (2000, 200));
+        assert_eq!(Ownership::Map(map.clone()).present(1001, 101, &accounts), (1001, 101));
+        assert_eq!(Ownership::Passwd(map.clone()).present(1000, 100, &accounts), (2000, 200));
+        assert_eq!(Ownership::Passwd(map).present(1001, 101, &accounts), (3001, 101));
+    }
+
+    #[test]
+    fn pairs() {
+        assert_eq!(pair("1000:2000"), Ok((1000, 2000)));
+        assert!(pair("1000").is_err());
+        assert!(pair("alice:2000").is_err());
+        assert!(pair("1000:-1").is_err());
+    }
+
+    #[test]
+    fn accounts_by_name() {
+        let (source, host) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for root in &[source.path(), host.path()] {
+            fs::create_dir(root.join("etc")).unwrap();
+        }
+        fs::write(source.path().join("etc/passwd"), "root:x:0:0:root:/root:/bin/sh\n# comment\nalice:x:1000:1000::/home/alice:/bin/sh\nbob:x:1001:1001::/home/bob:/bin/sh\nbroken\n").unwrap();
+        fs::write(host.path().join("etc/passwd"), "root:x:0:0:root:/root:/bin/sh\nalice:x:1500:1500::/home/alice:/bin/sh\n").unwrap();
+        fs::write(source.path().join("etc/group"), "users:x:100:alice,bob\n").unwrap();
+        let accounts = accounts(&[source.path().to_path_buf()], host.path());
+        assert_eq!(accounts.users, vec![(0, 0), (1000, 1500)].into_iter().collect());
+        assert_eq!(accounts.groups, HashMap::new());
+    }
+
+    #[test]
+    fn accounts_of_branches() {
+        let (first, second, host) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for root in &[first.path(), second.path(), host.path()] {
+            fs::create_dir(root.join("etc")).unwrap();
+        }
+        fs::write(first.path().join("etc/passwd"), "alice:x:1000:1000::/home/alice:/bin/sh
+").unwrap();
+        fs::write(second.path().join("etc/passwd"), "bob:x:1000:1000::/home/bob:/bin/sh
+carol:x:1001:1001::/home/carol:/bin/sh
+").unwrap();
+        fs::write(second.path().join("etc/group"), "staff:x:50:
+").unwrap();
+        fs::write(host.path().join("etc/passwd"), "alice:x:2000:2000::/home/alice:/bin/sh
+bob:x:2001:2001::/home/bob:/bin/sh
+carol:x:2002:2002::/home/carol:/bin/sh
+").unwrap();
+        fs::write(host.path().join("etc/group"), "staff:x:60:
+").unwrap();
+        let branches = [first.path().to_path_buf(), second.path().to_path_buf(), first.path().join("missing")];
+        let accounts = accounts(&branches, host.path());
+        assert_eq!(accounts.users, vec![(1000, 2000), (1001, 2002)].into_iter().collect());
+        assert_eq!(accounts.groups, vec![(50, 60)].into_iter().collect());
+    }
+
+    #[test]
+    fn accounts_not_followed() {
+        let (source, host) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::create_dir(source.path().join("etc")).unwrap();
+        fs::create_dir(host.path().join("etc")).unwrap();
+        fs::write(host.path().join("etc/passwd"), "alice:x:1000:1000::/home/alice:/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink(host.path().join("etc/passwd"), source.path().join("etc/passwd")).unwrap();
+        let accounts = accounts(&[source.path().to_path_buf()], host.path());
+        assert_eq!(accounts, IdMap::default());
+    }
+}
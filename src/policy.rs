@@ -0,0 +1,166 @@
+//! Policies deciding which operations are permitted through the mount.
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use fuse::Request;
+use libc::{c_int, EPERM};
+
+use crate::attributes::Attributes;
+use crate::config::Settings;
+
+/// Kind of filesystem operation being requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub enum Operation {
+    Lookup, Getattr, Setattr, Readlink, Mknod, Mkdir, Unlink, Rmdir, Symlink, Rename, Link,
+    Open, Read, Write, Flush, Release, Fsync, Opendir, Readdir, Fsyncdir, Statfs,
+    Setxattr, Getxattr, Listxattr, Removexattr, Access, Create, Getlk, Setlk, Bmap,
+}
+
+/// Identity of the process making a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Requester {
+    /// User id of the requesting process.
+    pub uid: u32,
+    /// Group id of the requesting process.
+    pub gid: u32,
+    /// Process id of the requesting process.
+    pub pid: u32,
+}
+
+impl From<&Request<'_>> for Requester {
+    fn from(req: &Request) -> Requester {
+        Requester { uid: req.uid(), gid: req.gid(), pid: req.pid() }
+    }
+}
+
+/// Outcome of consulting a policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Perform the operation.
+    Allow,
+    /// Fail the operation with the given errno.
+    Deny(c_int),
+}
+
+/// Decides whether an operation on a source path may proceed.
+///
+/// Consulted for every request but `forget` and `releasedir`, which only release what the kernel
+/// has already given up, and cannot be refused.
+pub trait OperationPolicy: Debug + Send + Sync {
+    /// Consulted before `operation` is applied to `path` on behalf of `requester`.
+    ///
+    /// `attributes` are those of the file at `path`, given for `Unlink` and `Rmdir` (unless they
+    /// could not be read), so that deletions may be decided on without reading them again.
+    fn check(&self, operation: Operation, path: &Path, attributes: Option<&Attributes>, requester: &Requester) -> Decision;
+}
+
+/// Read and delete, but deny anything which would create or change files, and the deletion of
+/// immutable or append-only files, with EPERM.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultPolicy;
+
+impl OperationPolicy for DefaultPolicy {
+    fn check(&self, operation: Operation, _path: &Path, attributes: Option<&Attributes>, _requester: &Requester) -> Decision {
+        use Operation::*;
+        match operation {
+            Setattr | Mknod | Mkdir | Symlink | Rename | Link | Write | Setxattr | Removexattr | Create | Setlk => Decision::Deny(EPERM),
+            Unlink | Rmdir => match attributes {
+                Some(attr) if attr.immutable || attr.append => Decision::Deny(EPERM),
+                _ => Decision::Allow,
+            },
+            _ => Decision::Allow,
+        }
+    }
+}
+
+/// `DefaultPolicy`, adjusted by the `allow` and `deny_errno` settings of the configuration.
+#[derive(Debug)]
+pub struct SettingsPolicy {
+    settings: Arc<RwLock<Settings>>,
+}
+
+impl SettingsPolicy {
+    /// Policy following `settings`, which may be changed while mounted.
+    pub fn new(settings: Arc<RwLock<Settings>>) -> SettingsPolicy {
+        SettingsPolicy { settings }
+    }
+}
+
+impl OperationPolicy for SettingsPolicy {
+    fn check(&self, operation: Operation, path: &Path, attributes: Option<&Attributes>, requester: &Requester) -> Decision {
+        let settings = self.settings.read().unwrap();
+        match operation {
+            Operation::Unlink if !settings.allow.unlink => Decision::Deny(settings.deny_errno),
+            Operation::Rmdir if !settings.allow.rmdir => Decision::Deny(settings.deny_errno),
+            _ => match DefaultPolicy.check(operation, path, attributes, requester) {
+                Decision::Deny(_) => Decision::Deny(settings.deny_errno),
+                Decision::Allow => Decision::Allow,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::EROFS;
+
+    const ROOT: Requester = Requester { uid: 0, gid: 0, pid: 1 };
+    const FS_IMMUTABLE_FL: c_int = 0x10;
+    const FS_APPEND_FL: c_int = 0x20;
+
+    /// Set the inode flags of `path`, as `chattr` does.
+    fn set_flags(path: &Path, flags: c_int) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let file = std::fs::File::open(path)?;
+        match unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error())
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let path = Path::new("t");
+        assert_eq!(DefaultPolicy.check(Operation::Read, path, None, &ROOT), Decision::Allow);
+        assert_eq!(DefaultPolicy.check(Operation::Unlink, path, None, &ROOT), Decision::Allow);
+        assert_eq!(DefaultPolicy.check(Operation::Write, path, None, &ROOT), Decision::Deny(EPERM));
+    }
+
+    #[test]
+    fn immutable_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let unlink = || DefaultPolicy.check(Operation::Unlink, &file, Attributes::of(&file).ok().as_ref(), &ROOT);
+        match set_flags(&file, FS_IMMUTABLE_FL) {
+            // Without CAP_LINUX_IMMUTABLE, or on a file system without inode flags (tmpfs, overlay).
+            Err(ref e) if [Some(EPERM), Some(libc::ENOTTY), Some(libc::EOPNOTSUPP)].contains(&e.raw_os_error()) => {
+                eprintln!("skipped: the immutable flag cannot be set here ({})", e);
+                return;
+            },
+            result => result.unwrap()
+        }
+        assert_eq!(unlink(), Decision::Deny(EPERM));
+        set_flags(&file, FS_APPEND_FL).unwrap();
+        assert_eq!(unlink(), Decision::Deny(EPERM));
+        set_flags(&file, 0).unwrap();
+        assert_eq!(unlink(), Decision::Allow);
+    }
+
+    #[test]
+    fn settings_policy() {
+        let settings = Arc::new(RwLock::new(Settings::default()));
+        let policy = SettingsPolicy::new(settings.clone());
+        let path = Path::new("t");
+        assert_eq!(policy.check(Operation::Unlink, path, None, &ROOT), Decision::Allow);
+
+        settings.write().unwrap().allow.unlink = false;
+        settings.write().unwrap().deny_errno = EROFS;
+        assert_eq!(policy.check(Operation::Unlink, path, None, &ROOT), Decision::Deny(EROFS));
+        assert_eq!(policy.check(Operation::Mkdir, path, None, &ROOT), Decision::Deny(EROFS));
+        assert_eq!(policy.check(Operation::Rmdir, path, None, &ROOT), Decision::Allow);
+    }
+}
@@ -0,0 +1,222 @@
+//! Running a mount as a service: daemonizing, serving requests, and reacting to signals until
+//! unmounted. Shared by the `decofs` and `mount.decofs` binaries.
+use std::{env, fs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::builder::DecoFsBuilder;
+use crate::config::{Config, Settings};
+use crate::daemon::{self, Daemon, Pidfile};
+use crate::options::MountOptions;
+
+/// Everything needed to mount a filesystem and serve it until it is unmounted.
+#[derive(Debug)]
+pub struct Service {
+    /// Directory on which the filesystem is mounted.
+    pub mountpoint: PathBuf,
+    /// Directories merged and exposed through the mount, in order of precedence.
+    pub sources: Vec<PathBuf>,
+    /// Options passed to `fuse::mount`.
+    pub options: MountOptions,
+    /// Whether to detach and run in the background.
+    pub daemon: bool,
+    /// File to which the process id is written.
+    pub pidfile: Option<PathBuf>,
+    /// Configuration file, re-read on SIGHUP.
+    pub config_path: Option<PathBuf>,
+    /// Contents of the configuration file, or defaults.
+    pub config: Config,
+}
+
+impl Service {
+    /// Check that the sources and mountpoint are usable, and make all paths absolute.
+    pub fn validate(mut self) -> Result<Service, String> {
+        if self.sources.is_empty() {
+            return Err("no source given".to_string());
+        }
+        self.sources.iter().try_for_each(|source| validate_source(source))?;
+        validate_mountpoint(&self.mountpoint, self.options.contains("nonempty"))?;
+        // Daemons change directory, so resolve relative paths up-front.
+        self.mountpoint = canonicalize(&self.mountpoint)?;
+        self.sources = self.sources.iter().map(|source| canonicalize(source)).collect::<Result<_, _>>()?;
+        if let Some(path) = self.config_path.take() {
+            self.config_path = Some(canonicalize(&path)?);
+        }
+        if let Some(path) = self.pidfile.take() {
+            self.pidfile = Some(daemon::absolute(&path).map_err(|e| e.to_string())?);
+        }
+        if let Some(path) = self.config.inode_store.take() {
+            self.config.inode_store = Some(daemon::absolute(&path).map_err(|e| e.to_string())?);
+        }
+        Ok(self)
+    }
+
+    /// Initialise logging, from `RUST_LOG` or else the `log` configuration setting.
+    pub fn init_logger(&self) {
+        let mut logger = env_logger::Builder::from_default_env();
+        if let (Err(_), Some(filters)) = (env::var("RUST_LOG"), &self.config.log) {
+            logger.parse_filters(filters);
+        }
+        logger.init();
+    }
+
+    /// Mount the filesystem, daemonizing first if requested, and serve requests until it is
+    /// unmounted. Must be called before any threads are started.
+    pub fn run(&self) -> Result<(), String> {
+        let daemon = match self.daemon {
+            true => Some(daemon::daemonize().map_err(|e| format!("failed to daemonize: {}", e))?),
+            false => None
+        };
+        let result = self.serve(daemon);
+        if let Err(e) = &result {
+            error!("{}", e);
+        }
+        result
+    }
+
+    fn serve(&self, daemon: Option<Daemon>) -> Result<(), String> {
+        let _pidfile = match &self.pidfile {
+            Some(path) => Some(Pidfile::create(path).map_err(|e| format!("failed to write pidfile {:?}: {}", path, e))?),
+            None => None
+        };
+
+        match daemon::raise_open_files_limit() {
+            Ok(limit) => info!("open files limit {}", limit),
+            Err(e) => warn!("failed to raise open files limit: {}", e)
+        }
+        let settings = Arc::new(RwLock::new(self.config.settings()));
+        let mut builder = self.sources[1..].iter()
+            .fold(DecoFsBuilder::new(&self.sources[0]), |builder, branch| builder.branch(branch))
+            .precedence(self.config.precedence)
+            .settings(settings.clone())
+            .threads(self.config.threads)
+            .watch(self.config.watch);
+        if let Some(path) = &self.config.inode_store {
+            builder = builder.inode_store(path);
+        }
+        let session = builder.build()
+            .mount(&self.mountpoint, &self.options)
+            .map_err(|e| format!("failed to mount {:?}: {}", self.mountpoint, e));
+        if let Some(daemon) = daemon {
+            daemon.ready(&session.as_ref().map(|_| ()).map_err(String::clone));
+        }
+        let session = session?;
+
+        let reload = {
+            let (path, config, sources, stats) = (self.config_path.clone(), self.config.clone(), self.sources.clone(), session.stats());
+            move || {
+                info!("{} inodes in table, {} reserved, {} source changes seen", stats.inodes(), stats.reserved_inodes(), stats.source_changes());
+                match &path {
+                    Some(path) => reload(path, &config, &sources, &settings),
+                    None => {
+                        info!("no config file to reload, reading accounts again");
+                        settings.write().unwrap().read_accounts(&sources);
+                    }
+                }
+            }
+        };
+        let (signals, handler) = daemon::handle_signals(&self.mountpoint, reload)
+            .map_err(|e| format!("failed to install signal handlers: {}", e))?;
+        let result = session.run();
+        signals.close();
+        let _ = handler.join();
+        result.map_err(|e| format!("session failed: {}", e))
+    }
+}
+
+/// Re-read the config file at `path`, applying any settings which do not require a remount, and
+/// the accounts of `sources`.
+fn reload(path: &Path, current: &Config, sources: &[PathBuf], settings: &RwLock<Settings>) {
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to reload: {}", e);
+            return;
+        }
+    };
+    if (&config.source, &config.branches, &config.precedence, &config.mountpoint, &config.options, &config.log, &config.inode_store, &config.threads, &config.watch)
+        != (&current.source, &current.branches, &current.precedence, &current.mountpoint, &current.options, &current.log, &current.inode_store, &current.threads, &current.watch) {
+        warn!("Changes to source, branches, precedence, mountpoint, options, log, inode_store, threads and watch require a remount, and have been ignored");
+    }
+    let mut reloaded = config.settings();
+    reloaded.read_accounts(sources);
+    *settings.write().unwrap() = reloaded;
+    info!("Reloaded {:?}", path);
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, String> {
+    fs::canonicalize(path).map_err(|e| format!("{:?}: {}", path, e))
+}
+
+/// The source must be a directory we are able to list.
+fn validate_source(path: &Path) -> Result<(), String> {
+    match fs::metadata(path) {
+        Ok(ref attr) if attr.is_dir() => (),
+        Ok(_) => return Err(format!("source {:?} is not a directory", path)),
+        Err(e) => return Err(format!("source {:?}: {}", path, e)),
+    };
+    fs::read_dir(path)
+        .map(|_| ())
+        .map_err(|e| format!("source {:?} is not readable: {}", path, e))
+}
+
+/// The mountpoint must be an existing directory, which is empty unless `nonempty` is set.
+fn validate_mountpoint(path: &Path, nonempty: bool) -> Result<(), String> {
+    match fs::metadata(path) {
+        Ok(ref attr) if attr.is_dir() => (),
+        Ok(_) => return Err(format!("mountpoint {:?} is not a directory", path)),
+        Err(e) => return Err(format!("mountpoint {:?}: {}", path, e)),
+    };
+    let mut entries = fs::read_dir(path)
+        .map_err(|e| format!("mountpoint {:?} is not readable: {}", path, e))?;
+    match entries.next() {
+        Some(_) if nonempty => Ok(()),
+        None => Ok(()),
+        Some(_) => Err(format!("mountpoint {:?} is not empty", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn source_missing() {
+        let mountpoint = tempdir().unwrap();
+        let source = mountpoint.path().join("missing");
+        assert!(validate_source(&source).is_err());
+    }
+
+    #[test]
+    fn source_not_directory() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("file");
+        fs::write(&source, "file").unwrap();
+        assert!(validate_source(&source).is_err());
+    }
+
+    #[test]
+    fn mountpoint_not_empty() {
+        let mountpoint = tempdir().unwrap();
+        fs::write(mountpoint.path().join("file"), "file").unwrap();
+        assert!(validate_mountpoint(mountpoint.path(), false).is_err());
+        assert!(validate_mountpoint(mountpoint.path(), true).is_ok());
+    }
+
+    #[test]
+    fn relative_pidfile() {
+        let mountpoint = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let service = Service {
+            mountpoint: mountpoint.path().to_path_buf(),
+            sources: vec![source.path().to_path_buf()],
+            options: MountOptions::default(),
+            daemon: true,
+            pidfile: Some(PathBuf::from("decofs.pid")),
+            config_path: None,
+            config: Config::default(),
+        }.validate().unwrap();
+        assert_eq!(service.pidfile, Some(env::current_dir().unwrap().join("decofs.pid")));
+    }
+}
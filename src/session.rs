@@ -0,0 +1,189 @@
+//! Mounting a `DecoFS`, in the foreground or on a background thread.
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use libc::EPERM;
+
+use crate::filesystem::DecoFS;
+use crate::invalidate;
+use crate::options::MountOptions;
+use crate::stats::Stats;
+
+/// Held while mounting, so that the connection to the kernel each mount opens can be told apart.
+static MOUNTING: Mutex<()> = Mutex::new(());
+
+/// A mounted filesystem, which serves requests once `run` is called.
+#[derive(Debug)]
+pub struct Session {
+    inner: fuse::Session<DecoFS>,
+}
+
+/// A filesystem served on a background thread, which is unmounted when the handle is dropped.
+#[derive(Debug)]
+pub struct MountHandle {
+    mountpoint: PathBuf,
+    ready: Arc<Ready>,
+    stats: Arc<Stats>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+/// Signalled once the kernel has initialised the session.
+#[derive(Debug, Default)]
+pub(crate) struct Ready {
+    initialized: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl DecoFS {
+    /// Mount the filesystem on `mountpoint`.
+    ///
+    /// Requests made to the mount block until `Session::run` is called.
+    pub fn mount<P: AsRef<Path>>(mut self, mountpoint: P, options: &MountOptions) -> io::Result<Session> {
+        self.open_inode_store()?;
+        let options = options.to_args();
+        let options = options.iter()
+            .map(|o| o.as_ref())
+            .collect::<Vec<&OsStr>>();
+        // fuse keeps its descriptor on `/dev/fuse` to itself, so it is found as the one opened.
+        let (mut inner, connection) = {
+            let _mounting = MOUNTING.lock().unwrap_or_else(PoisonError::into_inner);
+            let before = invalidate::connections();
+            let inner = fuse::Session::new(self, mountpoint.as_ref(), &options)?;
+            let opened: Vec<_> = invalidate::connections().into_iter().filter(|fd| !before.contains(fd)).collect();
+            (inner, match opened[..] {
+                [fd] => Some(fd),
+                _ => None
+            })
+        };
+        inner.filesystem.watch_sources(connection)?;
+        Ok(Session { inner })
+    }
+
+    /// Mount the filesystem on `mountpoint`, serving requests on a background thread.
+    pub fn spawn_mount<P: AsRef<Path>>(self, mountpoint: P, options: &MountOptions) -> io::Result<MountHandle> {
+        self.mount(mountpoint, options)?.spawn()
+    }
+}
+
+impl Session {
+    /// Directory on which the filesystem is mounted.
+    pub fn mountpoint(&self) -> &Path {
+        self.inner.mountpoint()
+    }
+
+    /// Metrics, updated while the filesystem is served.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.inner.filesystem.stats()
+    }
+
+    /// Serve requests until the filesystem is unmounted.
+    pub fn run(mut self) -> io::Result<()> {
+        let result = self.inner.run();
+        if !self.inner.destroyed {
+            self.inner.filesystem.teardown();
+        }
+        result
+    }
+
+    /// Serve requests on a background thread.
+    pub fn spawn(self) -> io::Result<MountHandle> {
+        let mountpoint = self.mountpoint().to_path_buf();
+        let ready = self.inner.filesystem.ready();
+        let stats = self.stats();
+        let thread = thread::Builder::new()
+            .name(format!("decofs {}", mountpoint.display()))
+            .spawn(move || self.run())?;
+        Ok(MountHandle { mountpoint, ready, stats, thread: Some(thread) })
+    }
+}
+
+impl MountHandle {
+    /// Directory on which the filesystem is mounted.
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Metrics, updated while the filesystem is served.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Whether the kernel has initialised the session, so that requests are being served.
+    pub fn is_ready(&self) -> bool {
+        *self.ready.initialized.lock().unwrap()
+    }
+
+    /// Wait up to `timeout` for the session to be initialised, returning whether it was.
+    pub fn wait_ready(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut initialized = self.ready.initialized.lock().unwrap();
+        while !*initialized {
+            let now = Instant::now();
+            if now >= deadline || self.thread.as_ref().is_none_or(|t| t.is_finished()) {
+                break;
+            }
+            // Wake periodically, in case the session ends without being initialised.
+            let timeout = (deadline - now).min(Duration::from_millis(100));
+            initialized = self.ready.condvar.wait_timeout(initialized, timeout).unwrap().0;
+        }
+        *initialized
+    }
+
+    /// Unmount the filesystem, returning the outcome of the session.
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        if !thread.is_finished() {
+            if let Err(e) = unmount(&self.mountpoint) {
+                // Joining would block until someone else unmounts, so leave the thread running.
+                error!("Failed to unmount {:?}: {}", self.mountpoint, e);
+                return Err(e);
+            }
+        }
+        thread.join()
+            .unwrap_or_else(|_| Err(io::Error::other("session thread panicked")))
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("Session on {:?} ended with error: {}", self.mountpoint, e);
+        }
+    }
+}
+
+impl Ready {
+    pub(crate) fn set(&self) {
+        *self.initialized.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// Unmount `mountpoint`, falling back to `fusermount` when not permitted to unmount directly.
+pub fn unmount(mountpoint: &Path) -> io::Result<()> {
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    if unsafe { libc::umount(mnt.as_ptr()) } == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(EPERM) {
+        return Err(e);
+    }
+    match Command::new("fusermount").arg("-u").arg(mountpoint).status()? {
+        status if status.success() => Ok(()),
+        status => Err(io::Error::other(format!("fusermount -u failed: {}", status))),
+    }
+}
@@ -0,0 +1,35 @@
+//! Metrics describing a mounted filesystem, readable while it is being served.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters updated by the filesystem as it serves requests.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub(crate) inodes: AtomicUsize,
+    pub(crate) reserved_inodes: AtomicUsize,
+    pub(crate) source_changes: AtomicUsize,
+    pub(crate) open_files: AtomicUsize,
+}
+
+impl Stats {
+    /// Number of inodes currently retained in the inode table, including the root.
+    pub fn inodes(&self) -> usize {
+        self.inodes.load(Ordering::Relaxed)
+    }
+
+    /// Number of inode numbers given to files listed by `readdir`, but not yet looked up, which
+    /// are kept so that a lookup gives them the same number.
+    pub fn reserved_inodes(&self) -> usize {
+        self.reserved_inodes.load(Ordering::Relaxed)
+    }
+
+    /// Number of names created, removed or modified on the sources other than through the mount,
+    /// while watching them.
+    pub fn source_changes(&self) -> usize {
+        self.source_changes.load(Ordering::Relaxed)
+    }
+
+    /// Number of files currently open through the mount.
+    pub fn open_files(&self) -> usize {
+        self.open_files.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,142 @@
+//! Persistent record of the inode numbers allocated to source files, so that they are unchanged
+//! after a restart.
+//!
+//! Records are appended to a text file as inodes are allocated, one per line:
+//! `DEV INO GENERATION NODEID`. A later record for the same `DEV INO` supersedes earlier ones (the
+//! inode number having been reused), which are dropped when the store is next opened.
+use std::{fs, io};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::inodes::{SourceId, ROOT};
+
+/// Inode number allocated to a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub(crate) generation: u32,
+    pub(crate) ino: u64,
+}
+
+/// Inode numbers allocated to source files, by source identity, backed by a file.
+#[derive(Debug)]
+pub(crate) struct InodeStore {
+    path: PathBuf,
+    file: File,
+    records: HashMap<SourceId, Record>,
+    next: u64,
+}
+
+fn invalid(path: &Path, line: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}, line {}: invalid record", path, line))
+}
+
+fn format((dev, ino): SourceId, record: &Record) -> String {
+    format!("{} {} {} {}\n", dev, ino, record.generation, record.ino)
+}
+
+fn parse(line: &str) -> Option<(SourceId, Record)> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    let id = (fields[0].parse().ok()?, fields[1].parse().ok()?);
+    Some((id, Record { generation: fields[2].parse().ok()?, ino: fields[3].parse().ok()? }))
+}
+
+impl InodeStore {
+    /// Open the store at `path`, creating it if it does not exist.
+    pub(crate) fn open(path: &Path) -> io::Result<InodeStore> {
+        let mut records = HashMap::new();
+        let mut count = 0;
+        match File::open(path) {
+            Ok(file) => for (i, line) in BufReader::new(file).lines().enumerate() {
+                let (id, record) = parse(&line?).ok_or_else(|| invalid(path, i + 1))?;
+                if record.ino <= ROOT {
+                    return Err(invalid(path, i + 1));
+                }
+                records.insert(id, record);
+                count += 1;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e)
+        }
+        if count > records.len() {
+            InodeStore::compact(path, &records)?;
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let next = records.values().map(|record| record.ino + 1).max().unwrap_or(ROOT + 1);
+        Ok(InodeStore { path: path.to_path_buf(), file, records, next })
+    }
+
+    /// Rewrite the store with only the current records.
+    fn compact(path: &Path, records: &HashMap<SourceId, Record>) -> io::Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".new");
+        let mut file = File::create(&temporary)?;
+        for (id, record) in records {
+            file.write_all(format(*id, record).as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Lowest inode number not yet allocated.
+    pub(crate) fn next(&self) -> u64 {
+        self.next
+    }
+
+    /// Inode number allocated to the source file `id`.
+    pub(crate) fn get(&self, id: SourceId) -> Option<&Record> {
+        self.records.get(&id)
+    }
+
+    /// Record the inode number allocated to the source file `id`.
+    pub(crate) fn insert(&mut self, id: SourceId, record: Record) -> io::Result<()> {
+        self.file.write_all(format(id, &record).as_bytes())
+            .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", self.path, e)))?;
+        self.next = self.next.max(record.ino + 1);
+        self.records.insert(id, record);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inodes");
+        {
+            let mut store = InodeStore::open(&path).unwrap();
+            assert_eq!(store.next(), ROOT + 1);
+            store.insert((1, 100), Record { generation: 7, ino: 2 }).unwrap();
+            store.insert((1, 101), Record { generation: 0, ino: 3 }).unwrap();
+            // Inode 100 reused for another file.
+            store.insert((1, 100), Record { generation: 8, ino: 4 }).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        let store = InodeStore::open(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert_eq!(store.next(), 5);
+        assert_eq!(store.get((1, 100)), Some(&Record { generation: 8, ino: 4 }));
+        assert_eq!(store.get((1, 101)), Some(&Record { generation: 0, ino: 3 }));
+
+        let mut store = InodeStore::open(&path).unwrap();
+        store.insert((2, 100), Record { generation: 1, ino: 5 }).unwrap();
+        assert_eq!(InodeStore::open(&path).unwrap().get((2, 100)), Some(&Record { generation: 1, ino: 5 }));
+    }
+
+    #[test]
+    fn invalid_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inodes");
+        for contents in &["1 100 0\n", "1 100 0 1\n", "1 100 0 2 -\n", "x 100 0 2\n"] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(InodeStore::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", contents);
+        }
+    }
+}
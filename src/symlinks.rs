@@ -0,0 +1,70 @@
+//! Presentation of symbolic links found on the source, which may otherwise lead out of the tree
+//! being decommissioned (e.g. `data -> /etc`, which the kernel would resolve on the host).
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+/// How symbolic links on the source are presented through the mount.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Symlinks {
+    /// As links, with their targets unchanged.
+    #[default]
+    Expose,
+    /// As the file or directory they lead to, if that is within the sources; other links are
+    /// hidden.
+    Follow,
+    /// As links, with targets rewritten to lead to the same place within the mount: absolute
+    /// targets are taken to be relative to the root of the source, and `..` stops at the root.
+    Rewrite,
+    /// Not at all.
+    Hide,
+}
+
+/// Target for a link at `link`, relative to the root of the mount, leading to wherever `target`
+/// would if the root of the mount were the root of the filesystem.
+pub(crate) fn confine(link: &Path, target: &Path) -> PathBuf {
+    let directory: Vec<Component> = link.parent().map(|parent| parent.components().collect()).unwrap_or_default();
+    let mut resolved = match target.is_absolute() {
+        true => Vec::new(),
+        false => directory.clone()
+    };
+    for component in target.components() {
+        match component {
+            Component::RootDir | Component::Prefix(_) => resolved.clear(),
+            Component::CurDir => (),
+            Component::ParentDir => { resolved.pop(); },
+            Component::Normal(_) => resolved.push(component)
+        }
+    }
+    let common = directory.iter().zip(&resolved).take_while(|(a, b)| a == b).count();
+    let confined: PathBuf = (common..directory.len()).map(|_| Component::ParentDir)
+        .chain(resolved[common..].iter().cloned())
+        .collect();
+    match confined.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => confined
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute() {
+        assert_eq!(confine(Path::new("data"), Path::new("/etc")), PathBuf::from("etc"));
+        assert_eq!(confine(Path::new("home/user/passwd"), Path::new("/etc/passwd")), PathBuf::from("../../etc/passwd"));
+        assert_eq!(confine(Path::new("home/user/link"), Path::new("/home/user/docs")), PathBuf::from("docs"));
+        assert_eq!(confine(Path::new("home/link"), Path::new("/")), PathBuf::from(".."));
+        assert_eq!(confine(Path::new("link"), Path::new("/")), PathBuf::from("."));
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(confine(Path::new("home/user/link"), Path::new("docs/./file")), PathBuf::from("docs/file"));
+        assert_eq!(confine(Path::new("home/user/link"), Path::new("../other")), PathBuf::from("../other"));
+        assert_eq!(confine(Path::new("home/link"), Path::new("../../../etc")), PathBuf::from("../etc"));
+        assert_eq!(confine(Path::new("link"), Path::new("..")), PathBuf::from("."));
+    }
+}
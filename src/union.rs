@@ -0,0 +1,329 @@
+//! Union of several source directories ("branches"), presented as a single tree.
+//!
+//! Paths within the mount are resolved against each branch in turn. Where a name exists in more
+//! than one branch, the `Precedence` decides which entry is presented; directories are merged.
+//!
+//! Each branch is only ever looked into through `beneath`, so that no path within it, however
+//! its directories are replaced, leads outside it.
+use std::{fs, io};
+use std::collections::HashSet;
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use libc::{c_int, ENOTEMPTY, EXDEV, O_DIRECTORY, O_PATH};
+use serde::Deserialize;
+
+use crate::attributes::Attributes;
+use crate::beneath;
+
+/// Which branch's entry is presented when a name exists in more than one branch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precedence {
+    /// The entry in the earliest branch, in the order given.
+    #[default]
+    First,
+    /// The most recently modified entry, with ties going to the earliest branch.
+    Newest,
+}
+
+/// Filesystem statistics, in units of `frsize` blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Statfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+/// Source directories, in precedence order.
+#[derive(Debug)]
+pub(crate) struct Union {
+    branches: Vec<PathBuf>,
+    /// The branches with symbolic links resolved, as the kernel reports paths within them.
+    canonical: Vec<PathBuf>,
+    precedence: Precedence,
+}
+
+impl Union {
+    pub(crate) fn new(branches: Vec<PathBuf>, precedence: Precedence) -> Union {
+        assert!(!branches.is_empty(), "a union needs at least one branch");
+        let canonical = branches.iter().map(|branch| fs::canonicalize(branch).unwrap_or_else(|_| branch.clone())).collect();
+        Union { branches, canonical, precedence }
+    }
+
+    /// Source directories, in precedence order.
+    pub(crate) fn branches(&self) -> &[PathBuf] {
+        &self.branches
+    }
+
+    /// `path`, relative to the root of the mount, within `branch`.
+    fn join(branch: &Path, path: &Path) -> PathBuf {
+        match path.as_os_str().is_empty() {
+            true => branch.to_path_buf(),
+            false => branch.join(path)
+        }
+    }
+
+    /// Entries for `path` which exist, in branch order.
+    fn candidates(&self, path: &Path) -> Vec<(PathBuf, Attributes)> {
+        self.branches.iter()
+            .filter_map(|branch| beneath::stat(branch, path, true).ok().map(|attr| (Union::join(branch, path), attr)))
+            .collect()
+    }
+
+    /// Source path presented for `path`: the entry chosen by precedence, or the path within the
+    /// first branch if there is none.
+    pub(crate) fn resolve(&self, path: &Path) -> PathBuf {
+        let mut candidates = self.candidates(path).into_iter();
+        let first = match candidates.next() {
+            Some(first) => first,
+            None => return Union::join(&self.branches[0], path),
+        };
+        match self.precedence {
+            Precedence::First => first.0,
+            Precedence::Newest => candidates
+                .fold(first, |newest, candidate| {
+                    match candidate.1.mtime > newest.1.mtime {
+                        true => candidate,
+                        false => newest
+                    }
+                }).0
+        }
+    }
+
+    /// Branches with a directory at `path`, merged to present it.
+    fn directories(&self, path: &Path) -> Vec<&Path> {
+        self.branches.iter()
+            .map(PathBuf::as_path)
+            .filter(|branch| beneath::open(branch, path, O_PATH | O_DIRECTORY, true).is_ok())
+            .collect()
+    }
+
+    /// Branch holding a source path, and the path relative to the root of the mount; `EXDEV` if
+    /// it is in none.
+    fn within<'a>(&self, source: &'a Path) -> io::Result<(&Path, &'a Path)> {
+        self.branches.iter()
+            .find_map(|branch| source.strip_prefix(branch).ok().map(|path| (branch.as_path(), path)))
+            .ok_or_else(|| io::Error::from_raw_os_error(EXDEV))
+    }
+
+    /// Open `source` with `flags`, resolving it only within its branch; symbolic links on the way
+    /// are only followed if `follow`.
+    pub(crate) fn open(&self, source: &Path, flags: c_int, follow: bool) -> io::Result<File> {
+        let (branch, path) = self.within(source)?;
+        beneath::open(branch, path, flags, follow)
+    }
+
+    /// Attributes of `source`, or of the link if it is one, resolving it only within its branch.
+    pub(crate) fn stat(&self, source: &Path, follow: bool) -> io::Result<Attributes> {
+        let (branch, path) = self.within(source)?;
+        beneath::stat(branch, path, follow)
+    }
+
+    /// Target of the symbolic link at `source`, resolving it only within its branch.
+    pub(crate) fn read_link(&self, source: &Path, follow: bool) -> io::Result<PathBuf> {
+        let (branch, path) = self.within(source)?;
+        beneath::read_link(branch, path, follow)
+    }
+
+    /// Size of the list of extended attribute names of `source`, resolving it only within its
+    /// branch.
+    pub(crate) fn listxattr_size(&self, source: &Path, follow: bool) -> io::Result<usize> {
+        let (branch, path) = self.within(source)?;
+        beneath::listxattr_size(branch, path, follow)
+    }
+
+    /// Remove the file at `source`, resolving it only within its branch.
+    pub(crate) fn remove_file(&self, source: &Path, follow: bool) -> io::Result<()> {
+        let (branch, path) = self.within(source)?;
+        beneath::remove(branch, path, false, follow)
+    }
+
+    /// Path relative to the root of the mount, and the source path within its branch, of the file
+    /// at `real`, a canonical path such as the kernel reports for an open file. `None` if it is
+    /// outside every branch.
+    pub(crate) fn locate(&self, real: &Path) -> Option<(PathBuf, PathBuf)> {
+        self.branches.iter().zip(&self.canonical)
+            .find_map(|(branch, canonical)| real.strip_prefix(canonical).ok().map(|path| (branch, path)))
+            .map(|(branch, path)| (path.to_path_buf(), Union::join(branch, path)))
+    }
+
+    /// Names in the directory presented at `path`, across all branches, in order of first
+    /// appearance, each with the source path presented for it.
+    pub(crate) fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, PathBuf)>> {
+        // Where there is no directory, listing the entry presented fails as it should.
+        let branches = match self.directories(path) {
+            branches if branches.is_empty() => vec![self.within(&self.resolve(path))?.0],
+            branches => branches
+        };
+        let mut seen = HashSet::new();
+        let mut names = Vec::new();
+        for branch in branches {
+            for name in beneath::read_dir(branch, path, true)? {
+                if seen.insert(name.clone()) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names.into_iter()
+            .map(|name| {
+                let source = self.resolve(&path.join(&name));
+                (name, source)
+            })
+            .collect())
+    }
+
+    /// Remove the directory presented at `source` from every branch, if all copies are empty.
+    pub(crate) fn remove_dir(&self, source: &Path, follow: bool) -> io::Result<()> {
+        let (branch, path) = self.within(source)?;
+        let directories = self.directories(path);
+        if directories.len() <= 1 {
+            return beneath::remove(branch, path, true, follow);
+        }
+        for directory in &directories {
+            if !beneath::read_dir(directory, path, follow)?.is_empty() {
+                return Err(io::Error::from_raw_os_error(ENOTEMPTY));
+            }
+        }
+        directories.into_iter().try_for_each(|branch| beneath::remove(branch, path, true, follow))
+    }
+
+    /// Statistics summed over the distinct filesystems holding the branches.
+    pub(crate) fn statfs(&self) -> io::Result<Statfs> {
+        let mut devices = HashSet::new();
+        let mut total: Option<Statfs> = None;
+        for branch in &self.branches {
+            if !devices.insert(fs::metadata(branch)?.st_dev()) {
+                continue;
+            }
+            let stat = statfs(branch)?;
+            total = Some(match total {
+                None => stat,
+                Some(total) => {
+                    // Scale to the block size of the first branch.
+                    let scale = |blocks: u64| blocks * u64::from(stat.frsize) / u64::from(total.frsize);
+                    Statfs {
+                        blocks: total.blocks + scale(stat.blocks),
+                        bfree: total.bfree + scale(stat.bfree),
+                        bavail: total.bavail + scale(stat.bavail),
+                        files: total.files + stat.files,
+                        ffree: total.ffree + stat.ffree,
+                        namelen: total.namelen.min(stat.namelen),
+                        ..total
+                    }
+                }
+            });
+        }
+        Ok(total.expect("a union has at least one branch"))
+    }
+}
+
+fn statfs(path: &Path) -> io::Result<Statfs> {
+    let cstr = CString::new(path.as_os_str().as_bytes())?;
+    let stat = unsafe {
+        let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+        if libc::statfs(cstr.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(Statfs {
+        blocks: stat.f_blocks,
+        bfree: stat.f_bfree,
+        bavail: stat.f_bavail,
+        files: stat.f_files,
+        ffree: stat.f_ffree,
+        bsize: stat.f_bsize as u32,
+        namelen: stat.f_namelen as u32,
+        frsize: stat.f_frsize as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use tempfile::{tempdir, TempDir};
+
+    fn branches() -> (TempDir, TempDir) {
+        let (a, b) = (tempdir().unwrap(), tempdir().unwrap());
+        fs::write(a.path().join("clash"), "a").unwrap();
+        fs::write(b.path().join("clash"), "b").unwrap();
+        fs::write(b.path().join("only_b"), "b").unwrap();
+        fs::create_dir(a.path().join("dir")).unwrap();
+        fs::create_dir(b.path().join("dir")).unwrap();
+        fs::write(b.path().join("dir").join("nested"), "b").unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn resolve_first() {
+        let (a, b) = branches();
+        let union = Union::new(vec![a.path().to_path_buf(), b.path().to_path_buf()], Precedence::First);
+        assert_eq!(union.resolve(Path::new("")), a.path());
+        assert_eq!(union.resolve(Path::new("clash")), a.path().join("clash"));
+        assert_eq!(union.resolve(Path::new("only_b")), b.path().join("only_b"));
+        assert_eq!(union.resolve(Path::new("missing")), a.path().join("missing"));
+    }
+
+    #[test]
+    fn resolve_newest() {
+        let (a, b) = branches();
+        let old = SystemTime::now() - Duration::from_secs(60);
+        File::options().write(true).open(a.path().join("clash")).unwrap().set_modified(old).unwrap();
+        let union = Union::new(vec![a.path().to_path_buf(), b.path().to_path_buf()], Precedence::Newest);
+        assert_eq!(union.resolve(Path::new("clash")), b.path().join("clash"));
+    }
+
+    #[test]
+    fn read_dir_merges() {
+        let (a, b) = branches();
+        let union = Union::new(vec![a.path().to_path_buf(), b.path().to_path_buf()], Precedence::First);
+        let mut entries = union.read_dir(Path::new("")).unwrap();
+        entries.sort();
+        assert_eq!(entries, vec![
+            (OsString::from("clash"), a.path().join("clash")),
+            (OsString::from("dir"), a.path().join("dir")),
+            (OsString::from("only_b"), b.path().join("only_b")),
+        ]);
+        assert_eq!(union.read_dir(Path::new("dir")).unwrap(), vec![(OsString::from("nested"), b.path().join("dir").join("nested"))]);
+    }
+
+    #[test]
+    fn locate() {
+        let (a, b) = branches();
+        let union = Union::new(vec![a.path().to_path_buf(), b.path().to_path_buf()], Precedence::First);
+        let real = fs::canonicalize(b.path().join("dir").join("nested")).unwrap();
+        assert_eq!(union.locate(&real), Some((PathBuf::from("dir/nested"), b.path().join("dir").join("nested"))));
+        assert_eq!(union.locate(&fs::canonicalize(a.path()).unwrap()), Some((PathBuf::new(), a.path().to_path_buf())));
+        assert_eq!(union.locate(Path::new("/elsewhere")), None);
+    }
+
+    #[test]
+    fn remove_dir_from_every_branch() {
+        let (a, b) = branches();
+        let union = Union::new(vec![a.path().to_path_buf(), b.path().to_path_buf()], Precedence::First);
+        let dir = union.resolve(Path::new("dir"));
+        assert_eq!(union.remove_dir(&dir, false).unwrap_err().raw_os_error(), Some(ENOTEMPTY));
+        fs::remove_file(b.path().join("dir").join("nested")).unwrap();
+        union.remove_dir(&dir, false).unwrap();
+        assert!(!a.path().join("dir").exists());
+        assert!(!b.path().join("dir").exists());
+    }
+
+    #[test]
+    fn statfs_counts_each_filesystem_once() {
+        let (a, b) = branches();
+        let single = Union::new(vec![a.path().to_path_buf()], Precedence::First).statfs().unwrap();
+        let union = Union::new(vec![a.path().to_path_buf(), b.path().to_path_buf()], Precedence::First).statfs().unwrap();
+        assert_eq!(union.blocks, single.blocks);
+    }
+}
@@ -0,0 +1,270 @@
+//! Watching of the source directories with inotify, for changes made other than through the
+//! mount.
+//!
+//! Every directory is watched (so a large tree may need `fs.inotify.max_user_watches` raised;
+//! directories beyond the limit are not watched), including those created while watching.
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use libc::{c_int, EFD_CLOEXEC, IN_ATTRIB, IN_CLOEXEC, IN_CREATE, IN_DELETE, IN_IGNORED, IN_ISDIR, IN_MODIFY, IN_MOVED_FROM, IN_MOVED_TO, IN_NONBLOCK, IN_ONLYDIR, IN_Q_OVERFLOW, POLLIN};
+
+const MASK: u32 = IN_CREATE | IN_DELETE | IN_MOVED_FROM | IN_MOVED_TO | IN_ATTRIB | IN_MODIFY | IN_ONLYDIR;
+
+/// What became of a name in a source directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// Created, or renamed to.
+    Created,
+    /// Deleted, or renamed away.
+    Removed,
+    /// The file it names was written to, or its attributes changed.
+    Modified,
+}
+
+/// Name created, removed or modified in a source directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Change {
+    /// Path relative to the root of the source directory.
+    pub(crate) path: PathBuf,
+    pub(crate) kind: Kind,
+}
+
+/// Watches the directories under a set of roots, until dropped.
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    stop: File,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn check(result: c_int) -> io::Result<c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result)
+    }
+}
+
+/// Watch descriptors of the directories watched, by path relative to the root of their source.
+#[derive(Debug)]
+struct Watches {
+    inotify: File,
+    roots: Vec<PathBuf>,
+    paths: HashMap<c_int, (usize, PathBuf)>,
+    /// Directories renamed away, until the kernel reports where to, by event cookie.
+    moving: HashMap<u32, (usize, PathBuf)>,
+}
+
+impl Watches {
+    /// Watch the directory `path` within root `root`, and every directory beneath it.
+    fn add(&mut self, root: usize, path: &Path) {
+        let source = match path.as_os_str().is_empty() {
+            true => self.roots[root].clone(),
+            false => self.roots[root].join(path)
+        };
+        let name = match CString::new(source.as_os_str().as_bytes()) {
+            Ok(name) => name,
+            Err(_) => return
+        };
+        match check(unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), name.as_ptr(), MASK) }) {
+            Ok(wd) => { self.paths.insert(wd, (root, path.to_path_buf())); },
+            Err(e) => {
+                warn!("not watching {:?}: {}", source, e);
+                return;
+            }
+        }
+        for entry in fs::read_dir(&source).into_iter().flatten().flatten() {
+            if entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false) {
+                self.add(root, &path.join(entry.file_name()));
+            }
+        }
+    }
+
+    /// Forget the directories at or beneath `path`, which are no longer in the tree.
+    fn remove(&mut self, root: usize, path: &Path) {
+        let gone: Vec<c_int> = self.paths.iter()
+            .filter(|(_, (r, p))| *r == root && p.starts_with(path))
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in gone {
+            unsafe { libc::inotify_rm_watch(self.inotify.as_raw_fd(), wd) };
+            self.paths.remove(&wd);
+        }
+    }
+
+    /// Record that the directory `from` is now at `to`.
+    fn rename(&mut self, root: usize, from: &Path, to: &Path) {
+        for (r, path) in self.paths.values_mut() {
+            if *r == root {
+                if let Ok(rest) = path.strip_prefix(from) {
+                    *path = to.join(rest);
+                }
+            }
+        }
+    }
+
+    /// Interpret one event, returning the change it describes.
+    fn event(&mut self, wd: c_int, mask: u32, cookie: u32, name: &OsStr) -> Option<Change> {
+        if mask & IN_Q_OVERFLOW != 0 {
+            warn!("source changes were missed, too many having been made at once");
+            return None;
+        }
+        if mask & IN_IGNORED != 0 {
+            self.paths.remove(&wd);
+            return None;
+        }
+        let (root, directory) = self.paths.get(&wd)?.clone();
+        // A change to a watched directory itself is also reported, by name, by the watch on its
+        // parent; only a root has none.
+        let path = match name.is_empty() {
+            true if directory.as_os_str().is_empty() => directory,
+            true => return None,
+            false => directory.join(name)
+        };
+        let is_dir = mask & IN_ISDIR != 0;
+        if mask & IN_MOVED_FROM != 0 && is_dir {
+            self.moving.insert(cookie, (root, path.clone()));
+        }
+        if mask & IN_MOVED_TO != 0 && is_dir {
+            match self.moving.remove(&cookie) {
+                Some((from_root, from)) if from_root == root => self.rename(root, &from, &path),
+                _ => self.add(root, &path)
+            }
+        }
+        if mask & IN_CREATE != 0 && is_dir {
+            self.add(root, &path);
+        }
+        let kind = match mask {
+            mask if mask & (IN_DELETE | IN_MOVED_FROM) != 0 => Kind::Removed,
+            mask if mask & (IN_CREATE | IN_MOVED_TO) != 0 => Kind::Created,
+            _ => Kind::Modified
+        };
+        Some(Change { path, kind })
+    }
+
+    /// Directories renamed away, with no event for where they went, have left the tree.
+    fn moved_away(&mut self) {
+        for (_, (root, path)) in std::mem::take(&mut self.moving) {
+            self.remove(root, &path);
+        }
+    }
+}
+
+impl Watcher {
+    /// Watch each of `roots`, calling `changed` as names are created, removed or modified beneath
+    /// them.
+    pub(crate) fn new<F>(roots: &[PathBuf], changed: F) -> io::Result<Watcher> where F: Fn(Change) + Send + 'static {
+        let inotify = unsafe { File::from_raw_fd(check(libc::inotify_init1(IN_NONBLOCK | IN_CLOEXEC))?) };
+        let stop = unsafe { File::from_raw_fd(check(libc::eventfd(0, EFD_CLOEXEC))?) };
+        let mut watches = Watches { inotify, roots: roots.to_vec(), paths: HashMap::new(), moving: HashMap::new() };
+        for root in 0..roots.len() {
+            watches.add(root, Path::new(""));
+        }
+        info!("watching {} source directories", watches.paths.len());
+        let stopped = stop.try_clone()?;
+        let thread = thread::Builder::new()
+            .name("decofs-watcher".to_string())
+            .spawn(move || watch(watches, &stopped, changed))?;
+        Ok(Watcher { stop, thread: Some(thread) })
+    }
+}
+
+fn watch<F: Fn(Change)>(mut watches: Watches, stop: &File, changed: F) {
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let mut fds = [
+            libc::pollfd { fd: watches.inotify.as_raw_fd(), events: POLLIN, revents: 0 },
+            libc::pollfd { fd: stop.as_raw_fd(), events: POLLIN, revents: 0 },
+        ];
+        if let Err(e) = check(unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) }) {
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                _ => {
+                    warn!("stopped watching the source: {}", e);
+                    return;
+                }
+            }
+        }
+        if fds[1].revents != 0 {
+            return;
+        }
+        let read = match (&watches.inotify).read(&mut buffer) {
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("stopped watching the source: {}", e);
+                return;
+            }
+        };
+        let mut offset = 0;
+        while offset + size_of::<libc::inotify_event>() <= read {
+            let event = unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event) };
+            let start = offset + size_of::<libc::inotify_event>();
+            offset = start + event.len as usize;
+            let name = &buffer[start..offset.min(read)];
+            let name = OsStr::from_bytes(&name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())]);
+            if let Some(change) = watches.event(event.wd, event.mask, event.cookie, name) {
+                changed(change);
+            }
+        }
+        watches.moved_away();
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let _ = (&self.stop).write_all(&1u64.to_ne_bytes());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Duration;
+
+    fn next(changes: &Receiver<Change>) -> (PathBuf, Kind) {
+        let change = changes.recv_timeout(Duration::from_secs(5)).unwrap();
+        (change.path, change.kind)
+    }
+
+    #[test]
+    fn reports_changes() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("a/b")).unwrap();
+        let (sender, changes) = channel();
+        let watcher = Watcher::new(&[root.path().to_path_buf()], move |change| sender.send(change).unwrap()).unwrap();
+
+        fs::write(root.path().join("a/b/file"), "").unwrap();
+        assert_eq!(next(&changes), (PathBuf::from("a/b/file"), Kind::Created));
+        fs::set_permissions(root.path().join("a/b/file"), fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(next(&changes), (PathBuf::from("a/b/file"), Kind::Modified));
+        fs::remove_file(root.path().join("a/b/file")).unwrap();
+        assert_eq!(next(&changes), (PathBuf::from("a/b/file"), Kind::Removed));
+
+        fs::create_dir(root.path().join("new")).unwrap();
+        assert_eq!(next(&changes), (PathBuf::from("new"), Kind::Created));
+        fs::write(root.path().join("new/file"), "").unwrap();
+        assert_eq!(next(&changes), (PathBuf::from("new/file"), Kind::Created));
+
+        fs::rename(root.path().join("a"), root.path().join("moved")).unwrap();
+        assert_eq!(next(&changes), (PathBuf::from("a"), Kind::Removed));
+        assert_eq!(next(&changes), (PathBuf::from("moved"), Kind::Created));
+        fs::remove_dir(root.path().join("moved/b")).unwrap();
+        assert_eq!(next(&changes), (PathBuf::from("moved/b"), Kind::Removed));
+        fs::set_permissions(root.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        assert_eq!(next(&changes), (PathBuf::new(), Kind::Modified));
+
+        drop(watcher);
+        assert!(changes.recv_timeout(Duration::from_secs(5)).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Pool of threads serving requests, so that a slow request (such as a read from a failing disk)
+//! does not hold up others.
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Number of worker threads used unless configured otherwise.
+pub(crate) const DEFAULT_THREADS: usize = 8;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running jobs in the order submitted, as each becomes free.
+#[derive(Debug)]
+pub(crate) struct Workers {
+    sender: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Pool of `count` threads; with none, jobs run on the calling thread.
+    pub(crate) fn new(count: usize) -> Workers {
+        if count == 0 {
+            return Workers { sender: None, threads: Vec::new() };
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..count)
+            .map(|i| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("decofs-worker-{}", i))
+                    .spawn(move || work(&receiver))
+                    .expect("failed to start worker thread")
+            })
+            .collect();
+        Workers { sender: Some(sender), threads }
+    }
+
+    /// Run `job` on the next free thread.
+    pub(crate) fn run<F: FnOnce() + Send + 'static>(&self, job: F) {
+        match &self.sender {
+            Some(sender) => sender.send(Box::new(job)).expect("worker threads have exited"),
+            None => job()
+        }
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is released before running the job, so that others may take the next.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return
+        };
+        job();
+    }
+}
+
+impl Drop for Workers {
+    /// Finish the jobs already submitted, then stop the threads.
+    fn drop(&mut self) {
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn concurrent() {
+        let workers = Workers::new(2);
+        let barrier = Arc::new(Barrier::new(3));
+        for _ in 0..2 {
+            let barrier = barrier.clone();
+            // Each blocks until both are running.
+            workers.run(move || { barrier.wait(); });
+        }
+        barrier.wait();
+    }
+
+    #[test]
+    fn finishes_jobs_when_dropped() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let workers = Workers::new(2);
+        for _ in 0..10 {
+            let done = done.clone();
+            workers.run(move || { done.fetch_add(1, Ordering::SeqCst); });
+        }
+        drop(workers);
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn inline() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let counter = done.clone();
+        Workers::new(0).run(move || { counter.fetch_add(1, Ordering::SeqCst); });
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+}
@@ -2,21 +2,23 @@
 extern crate lazy_static;
 
 use assert_cmd::prelude::*; // Add methods on commands
-use std::path::PathBuf;
-use std::process::{Child, Command}; // Run programs
+use predicates::prelude::*; // Used for writing assertions
+use std::path::{Path, PathBuf};
+use std::process::Command; // Run programs
 use libc::EPERM;
 use std::{fs, thread, time};
+use rust_decofs::{DecoFsBuilder, Filter, MountHandle, MountOptions, Stats};
 
 use std::ops::{Deref, DerefMut};
 use std::sync::{Mutex, MutexGuard, PoisonError};
 
 struct FuseMounter {
-    child: Option<Child>,
+    handle: Option<MountHandle>,
 }
 
 impl FuseMounter {
     fn new() -> FuseMounter {
-        FuseMounter { child: None }
+        FuseMounter { handle: None }
     }
 
     fn source(&self) -> PathBuf {
@@ -28,7 +30,7 @@ impl FuseMounter {
     }
 
     fn empty_source(&self) -> Result<(), Box<dyn std::error::Error>> {
-        for entry in fs::read_dir(&self.source())? {
+        for entry in fs::read_dir(self.source())? {
             let path = entry?.path();
             println!("empty_source {:?}", path);
             match fs::metadata(&path)?.is_dir() {
@@ -40,21 +42,50 @@ impl FuseMounter {
     }
 
     fn mount(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut cmd = Command::main_binary()?;
-        cmd.arg(self.target()).arg(self.source());
-        self.child = Some(cmd.spawn()?);
-        thread::sleep(time::Duration::from_millis(1000));
+        let handle = DecoFsBuilder::new(self.source())
+            .build()
+            .spawn_mount(self.target(), &MountOptions::default())?;
+        assert!(handle.wait_ready(time::Duration::from_secs(5)));
+        self.handle = Some(handle);
         Ok(())
     }
 
     fn umount(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        Command::new("fusermount")
-            .arg("-u")
-            .arg(self.target())
-            .spawn()?
-            .wait()?;
-        let child = self.child.take();
-        child.unwrap().wait()?;
+        self.handle.take().unwrap().unmount()?;
+        Ok(())
+    }
+}
+
+/// A filesystem mounted on its own temporary directory, unmounted when dropped.
+struct TestMount {
+    handle: MountHandle,
+    mountpoint: tempfile::TempDir,
+}
+
+impl TestMount {
+    /// Mount the filesystem `build` makes of a builder on `source`, once the kernel has it ready.
+    fn new<F>(source: &Path, build: F) -> Result<TestMount, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(DecoFsBuilder) -> DecoFsBuilder,
+    {
+        let mountpoint = tempfile::tempdir()?;
+        let handle = build(DecoFsBuilder::new(source))
+            .build()
+            .spawn_mount(mountpoint.path(), &MountOptions::default())?;
+        assert!(handle.wait_ready(time::Duration::from_secs(5)));
+        Ok(TestMount { handle, mountpoint })
+    }
+
+    fn path(&self) -> &Path {
+        self.mountpoint.path()
+    }
+
+    fn stats(&self) -> &Stats {
+        self.handle.stats()
+    }
+
+    fn unmount(self) -> Result<(), Box<dyn std::error::Error>> {
+        self.handle.unmount()?;
         Ok(())
     }
 }
@@ -78,7 +109,7 @@ impl MutexMounter {
         }
     }
 
-    fn lock(&self) -> Result<MutexMounterGuard, PoisonError<MutexMounterGuard>> {
+    fn lock(&self) -> Result<MutexMounterGuard<'_>, PoisonError<MutexMounterGuard<'_>>> {
         MutexMounterGuard::new(self.mounter.lock().unwrap())
     }
 }
@@ -95,10 +126,9 @@ impl MutexMounterGuard<'_> {
 impl Drop for MutexMounterGuard<'_> {
     fn drop(&mut self) {
         println!("drop MutexMounterGuard");
-        match self.inner_guard.empty_source() {
-            Err(e) => println!("Failure emptying source: {:?}", e),
-            _ => ()
-        };
+        if let Err(e) = self.inner_guard.empty_source() {
+            println!("Failure emptying source: {:?}", e);
+        }
         self.inner_guard.umount().unwrap();
     }
 }
@@ -122,8 +152,7 @@ fn can_readthru() -> Result<(), Box<dyn std::error::Error>> {
     let actual = {
         let mounter = MOUNTER.lock()?;
         fs::write(mounter.source().join("read"), "world")?;
-        let actual = fs::read_to_string(mounter.target().join("read"))?;
-        actual
+        fs::read_to_string(mounter.target().join("read"))?
     };
     assert_eq!(actual, "world");
     Ok(())
@@ -133,8 +162,8 @@ fn can_readthru() -> Result<(), Box<dyn std::error::Error>> {
 fn cannot_writethru() -> Result<(), Box<dyn std::error::Error>> {
     let mounter = MOUNTER.lock()?;
     match fs::write(mounter.target().join("write"), "goodbye") {
-        Err(ref e) if e.raw_os_error() == Some(EPERM) => assert!(true),
-        _ => assert!(false)
+        Err(ref e) if e.raw_os_error() == Some(EPERM) => (),
+        r => panic!("expected EPERM, got {:?}", r)
     };
     Ok(())
 }
@@ -149,8 +178,8 @@ fn can_deletethru() -> Result<(), Box<dyn std::error::Error>> {
         fs::remove_file(target)
     };
     match actual {
-        Ok(_) => assert!(true),
-        Err(e) => {println!("error: {:?}", e);assert!(false);},
+        Ok(_) => (),
+        Err(e) => panic!("error: {:?}", e),
     };
     Ok(())
 }
@@ -160,8 +189,8 @@ fn cannot_rename() -> Result<(), Box<dyn std::error::Error>> {
     let mounter = MOUNTER.lock()?;
     fs::write(mounter.source().join("rename"), "rename")?;
     match fs::rename(mounter.target().join("rename"), mounter.target().join("renamed")) {
-        Err(ref e) if e.raw_os_error() == Some(EPERM) => assert!(true),
-        _ => assert!(false)
+        Err(ref e) if e.raw_os_error() == Some(EPERM) => (),
+        r => panic!("expected EPERM, got {:?}", r)
     };
     Ok(())
 }
@@ -170,8 +199,8 @@ fn cannot_rename() -> Result<(), Box<dyn std::error::Error>> {
 fn cannot_mkdir() -> Result<(), Box<dyn std::error::Error>> {
     let mounter = MOUNTER.lock()?;
     match fs::create_dir(mounter.target().join("mkdir")) {
-        Err(ref e) if e.raw_os_error() == Some(EPERM) => assert!(true),
-        _ => assert!(false)
+        Err(ref e) if e.raw_os_error() == Some(EPERM) => (),
+        r => panic!("expected EPERM, got {:?}", r)
     };
     Ok(())
 }
@@ -181,8 +210,636 @@ fn can_rmdir() -> Result<(), Box<dyn std::error::Error>> {
     let mounter = MOUNTER.lock()?;
     fs::create_dir(mounter.source().join("rmdir"))?;
     match fs::remove_dir(mounter.target().join("rmdir")) {
-        Ok(_) => assert!(true),
-        Err(e) => {println!("error: {:?}", e);assert!(false);},
+        Ok(_) => (),
+        Err(e) => panic!("error: {:?}", e),
+    };
+    Ok(())
+}
+
+#[test]
+fn mount_handle_unmounts_on_drop() -> Result<(), Box<dyn std::error::Error>> {
+    let mountpoint = tempfile::tempdir()?;
+    let source = tempfile::tempdir()?;
+    fs::write(source.path().join("read"), "world")?;
+    {
+        let handle = DecoFsBuilder::new(source.path())
+            .build()
+            .spawn_mount(mountpoint.path(), &MountOptions::default())?;
+        assert!(handle.wait_ready(time::Duration::from_secs(5)));
+        assert!(handle.is_ready());
+        assert_eq!(handle.mountpoint(), fs::canonicalize(mountpoint.path())?);
+        assert_eq!(fs::read_to_string(mountpoint.path().join("read"))?, "world");
+    }
+    assert!(!mountpoint.path().join("read").exists());
+    Ok(())
+}
+
+#[test]
+fn union_of_branches() -> Result<(), Box<dyn std::error::Error>> {
+    let (first, second) = (tempfile::tempdir()?, tempfile::tempdir()?);
+    fs::write(first.path().join("clash"), "first")?;
+    fs::write(second.path().join("clash"), "second")?;
+    fs::write(second.path().join("only_second"), "second")?;
+    let mount = TestMount::new(first.path(), |builder| builder
+        .branch(second.path())
+        .ttl(time::Duration::from_secs(0)))?;
+
+    let mut names = fs::read_dir(mount.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    names.sort();
+    assert_eq!(names, vec!["clash", "only_second"]);
+    assert_eq!(fs::read_to_string(mount.path().join("clash"))?, "first");
+
+    fs::remove_file(mount.path().join("only_second"))?;
+    assert!(!second.path().join("only_second").exists());
+    fs::remove_file(mount.path().join("clash"))?;
+    assert!(!first.path().join("clash").exists());
+    assert_eq!(fs::read_to_string(mount.path().join("clash"))?, "second");
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn filter_hides_paths() -> Result<(), Box<dyn std::error::Error>> {
+    let source = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("lost+found"))?;
+    fs::write(source.path().join("lost+found").join("orphan"), "orphan")?;
+    fs::write(source.path().join("visible"), "visible")?;
+    let filter = Filter::new(&[] as &[&str], &["/lost+found"])?;
+    let mount = TestMount::new(source.path(), |builder| builder
+        .filter(filter))?;
+
+    let names = fs::read_dir(mount.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(names, vec!["visible"]);
+    assert!(!mount.path().join("lost+found").exists());
+    match fs::remove_file(mount.path().join("lost+found").join("orphan")) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        r => panic!("expected ENOENT, got {:?}", r)
+    };
+    match fs::remove_dir(mount.path().join("lost+found")) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        r => panic!("expected ENOENT, got {:?}", r)
+    };
+    assert!(source.path().join("lost+found").join("orphan").exists());
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn root_is_inode_one() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::MetadataExt;
+    let source = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("dir"))?;
+    let mount = TestMount::new(source.path(), |builder| builder)?;
+    assert_eq!(fs::metadata(mount.path())?.ino(), 1);
+    assert_eq!(fs::metadata(mount.path().join("dir").join(".."))?.ino(), 1);
+    assert_ne!(fs::metadata(mount.path().join("dir"))?.ino(), fs::metadata(source.path().join("dir"))?.ino());
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn listed_inodes_match_lookups() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::{DirEntryExt, MetadataExt};
+    let source = tempfile::tempdir()?;
+    fs::write(source.path().join("file"), "")?;
+    fs::create_dir(source.path().join("dir"))?;
+    let mount = TestMount::new(source.path(), |builder| builder)?;
+    // Listed before ever being looked up.
+    for entry in fs::read_dir(mount.path())? {
+        let entry = entry?;
+        assert_eq!(entry.ino(), fs::metadata(entry.path())?.ino(), "{:?}", entry.file_name());
+    }
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn one_file_system_hides_nested_mounts() -> Result<(), Box<dyn std::error::Error>> {
+    let source = tempfile::tempdir()?;
+    let nested = source.path().join("nested");
+    fs::create_dir(&nested)?;
+    let target = std::ffi::CString::new(nested.to_str().unwrap())?;
+    let tmpfs = std::ffi::CString::new("tmpfs")?;
+    if unsafe { libc::mount(tmpfs.as_ptr(), target.as_ptr(), tmpfs.as_ptr(), 0, std::ptr::null()) } != 0 {
+        eprintln!("skipping: cannot mount tmpfs: {}", std::io::Error::last_os_error());
+        return Ok(());
+    }
+    fs::write(nested.join("file"), "nested")?;
+    fs::write(source.path().join("file"), "top")?;
+    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mount = TestMount::new(source.path(), |builder| builder
+            .one_file_system(true))?;
+        let names = fs::read_dir(mount.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(names, vec!["file"]);
+        assert!(!mount.path().join("nested").join("file").exists());
+        mount.unmount()?;
+        Ok(())
+    })();
+    unsafe { libc::umount(target.as_ptr()) };
+    result
+}
+
+#[test]
+#[ignore = "needs a writable /proc/sys/vm/drop_caches"]
+fn forget_evicts_inodes() -> Result<(), Box<dyn std::error::Error>> {
+    let source = tempfile::tempdir()?;
+    for i in 0..100 {
+        fs::write(source.path().join(i.to_string()), "file")?;
+    }
+    let mount = TestMount::new(source.path(), |builder| builder)?;
+    assert_eq!(mount.stats().inodes(), 1);
+    for i in 0..100 {
+        fs::metadata(mount.path().join(i.to_string()))?;
+    }
+    assert_eq!(mount.stats().inodes(), 101);
+
+    // Ask the kernel to drop unused dentries and inodes, which makes it forget them.
+    fs::write("/proc/sys/vm/drop_caches", "2")?;
+    for _ in 0..50 {
+        if mount.stats().inodes() == 1 {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    assert_eq!(mount.stats().inodes(), 1);
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn non_utf8_names() -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let source = tempfile::tempdir()?;
+    let dir = OsStr::from_bytes(b"r\xe9pertoire");
+    let name = OsStr::from_bytes(b"caf\xe9.txt");
+    fs::create_dir(source.path().join(dir))?;
+    fs::write(source.path().join(dir).join(name), "latin-1")?;
+    let mount = TestMount::new(source.path(), |builder| builder)?;
+
+    let names = fs::read_dir(mount.path().join(dir))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(names, vec![name]);
+    assert_eq!(fs::read_to_string(mount.path().join(dir).join(name))?, "latin-1");
+    fs::remove_file(mount.path().join(dir).join(name))?;
+    assert!(!source.path().join(dir).join(name).exists());
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn unlink_keeps_other_hard_links() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::MetadataExt;
+    let source = tempfile::tempdir()?;
+    fs::write(source.path().join("a"), "linked")?;
+    fs::hard_link(source.path().join("a"), source.path().join("b"))?;
+    fs::hard_link(source.path().join("a"), source.path().join("c"))?;
+    let mount = TestMount::new(source.path(), |builder| builder)?;
+
+    // Find "a" last, so that it is the name the inode was most recently found at.
+    let c = fs::metadata(mount.path().join("c"))?;
+    let b = fs::metadata(mount.path().join("b"))?;
+    let a = fs::metadata(mount.path().join("a"))?;
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(a.ino(), c.ino());
+    fs::remove_file(mount.path().join("a"))?;
+    assert!(!source.path().join("a").exists());
+    // A name removed behind the mount's back is skipped in favour of one which survives.
+    fs::remove_file(source.path().join("b"))?;
+    assert_eq!(fs::read_to_string(mount.path().join("c"))?, "linked");
+    assert_eq!(fs::metadata(mount.path().join("c"))?.nlink(), 1);
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn open_files_follow_renames_on_source() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::fs::MetadataExt;
+    let source = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("dir"))?;
+    fs::write(source.path().join("dir").join("file"), "moved")?;
+    let mount = TestMount::new(source.path(), |builder| builder
+        .ttl(time::Duration::from_secs(0)))?;
+
+    let mut file = fs::File::open(mount.path().join("dir").join("file"))?;
+    fs::rename(source.path().join("dir"), source.path().join("renamed"))?;
+    assert_eq!(file.metadata()?.len(), 5);
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    assert_eq!(contents, "moved");
+    assert_eq!(fs::read_to_string(mount.path().join("renamed").join("file"))?, "moved");
+
+    // Removed from the source, the file remains readable until closed, as it would locally.
+    fs::remove_file(source.path().join("renamed").join("file"))?;
+    assert_eq!(file.metadata()?.nlink(), 0);
+    file.seek(SeekFrom::Start(1))?;
+    contents.clear();
+    file.read_to_string(&mut contents)?;
+    assert_eq!(contents, "oved");
+    assert_eq!(mount.stats().open_files(), 1);
+    drop(file);
+    let start = time::Instant::now();
+    while mount.stats().open_files() > 0 && start.elapsed() < time::Duration::from_secs(5) {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    assert_eq!(mount.stats().open_files(), 0);
+    assert!(!mount.path().join("renamed").join("file").exists());
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn inode_store_keeps_inode_numbers() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::{DirEntryExt, MetadataExt};
+    let source = tempfile::tempdir()?;
+    let store = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("dir"))?;
+    fs::write(source.path().join("dir").join("b"), "b")?;
+    fs::write(source.path().join("a"), "a")?;
+    let inodes = |mount: &TestMount| -> Result<Vec<u64>, std::io::Error> {
+        ["dir/b", "a", "dir"].iter().map(|path| fs::metadata(mount.path().join(path)).map(|attr| attr.ino())).collect()
+    };
+
+    let mount = TestMount::new(source.path(), |builder| builder
+        .inode_store(store.path().join("inodes")))?;
+    let before = inodes(&mount)?;
+    mount.unmount()?;
+
+    let mount = TestMount::new(source.path(), |builder| builder
+        .inode_store(store.path().join("inodes")))?;
+    // Looked up in a different order, so that numbers would otherwise be allocated differently.
+    assert_eq!(fs::metadata(mount.path().join("a"))?.ino(), before[1]);
+    assert_eq!(inodes(&mount)?, before);
+    let listed = fs::read_dir(mount.path())?
+        .map(|entry| entry.map(|entry| (entry.file_name(), entry.ino())))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(listed.contains(&("a".into(), before[1])));
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn slow_reads_do_not_block_other_requests() -> Result<(), Box<dyn std::error::Error>> {
+    use rust_decofs::{Attributes, Decision, DefaultPolicy, Operation, OperationPolicy, Requester};
+    use std::sync::mpsc::{self, Receiver};
+
+    /// Holds up every read until released, as a failing disk would.
+    #[derive(Debug)]
+    struct StalledReads(Mutex<Receiver<()>>);
+    impl OperationPolicy for StalledReads {
+        fn check(&self, operation: Operation, path: &Path, attributes: Option<&Attributes>, requester: &Requester) -> Decision {
+            if let Operation::Read = operation {
+                self.0.lock().unwrap().recv().ok();
+            }
+            DefaultPolicy.check(operation, path, attributes, requester)
+        }
+    }
+
+    let source = tempfile::tempdir()?;
+    fs::write(source.path().join("file"), "slow")?;
+    let (release, stalled) = mpsc::channel();
+    let mount = TestMount::new(source.path(), |builder| builder
+        .policy(StalledReads(Mutex::new(stalled))))?;
+
+    let file = mount.path().join("file");
+    let reader = thread::spawn(move || fs::read_to_string(file));
+    thread::sleep(time::Duration::from_millis(100));
+    let (listed, listing) = mpsc::channel();
+    let root = mount.path().to_path_buf();
+    thread::spawn(move || listed.send(fs::read_dir(root).map(|entries| entries.count())));
+    assert_eq!(listing.recv_timeout(time::Duration::from_secs(5))??, 1);
+    assert!(!reader.is_finished());
+
+    release.send(())?;
+    assert_eq!(reader.join().unwrap()?, "slow");
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn special_file_types() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+    let source = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("dir"))?;
+    std::os::unix::fs::symlink("dir", source.path().join("link"))?;
+    let fifo = std::ffi::CString::new(source.path().join("fifo").to_str().unwrap())?;
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    let _socket = UnixListener::bind(source.path().join("socket"))?;
+    let mount = TestMount::new(source.path(), |builder| builder)?;
+
+    let link = mount.path().join("link");
+    assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+    assert_eq!(fs::read_link(&link)?, PathBuf::from("dir"));
+    assert!(fs::metadata(&link)?.is_dir());
+    assert!(fs::symlink_metadata(mount.path().join("fifo"))?.file_type().is_fifo());
+    assert!(fs::symlink_metadata(mount.path().join("socket"))?.file_type().is_socket());
+    let mut listed = fs::read_dir(mount.path())?
+        .map(|entry| entry.and_then(|entry| Ok((entry.file_name(), entry.file_type()?))))
+        .collect::<Result<Vec<_>, _>>()?;
+    listed.sort_by(|a, b| a.0.cmp(&b.0));
+    let kinds: Vec<_> = listed.iter().map(|(_, kind)| (kind.is_dir(), kind.is_symlink(), kind.is_fifo(), kind.is_socket())).collect();
+    assert_eq!(kinds, vec![(true, false, false, false), (false, false, true, false), (false, true, false, false), (false, false, false, true)]);
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn symlink_policies() -> Result<(), Box<dyn std::error::Error>> {
+    use rust_decofs::Symlinks;
+    use std::os::unix::fs::symlink;
+    let source = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("dir"))?;
+    fs::write(source.path().join("dir").join("file"), "inside")?;
+    symlink("dir", source.path().join("inside"))?;
+    symlink("/dir/file", source.path().join("absolute"))?;
+    symlink("/etc", source.path().join("escape"))?;
+    let mount_with = |symlinks| TestMount::new(source.path(), |builder| builder
+        .symlinks(symlinks));
+    let listing = |mount: &TestMount| -> Result<Vec<_>, std::io::Error> {
+        let mut names = fs::read_dir(mount.path())?.map(|entry| entry.map(|entry| entry.file_name())).collect::<Result<Vec<_>, _>>()?;
+        names.sort();
+        Ok(names)
+    };
+
+    let mount = mount_with(Symlinks::Follow)?;
+    assert!(fs::symlink_metadata(mount.path().join("inside"))?.is_dir());
+    assert_eq!(fs::read_to_string(mount.path().join("inside").join("file"))?, "inside");
+    assert_eq!(fs::symlink_metadata(mount.path().join("escape")).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(fs::symlink_metadata(mount.path().join("absolute")).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(listing(&mount)?, vec!["dir", "inside"]);
+    mount.unmount()?;
+
+    let mount = mount_with(Symlinks::Rewrite)?;
+    assert_eq!(fs::read_link(mount.path().join("escape"))?, PathBuf::from("etc"));
+    assert_eq!(fs::read_link(mount.path().join("inside"))?, PathBuf::from("dir"));
+    assert_eq!(fs::read_to_string(mount.path().join("absolute"))?, "inside");
+    mount.unmount()?;
+
+    let mount = mount_with(Symlinks::Hide)?;
+    assert!(!mount.path().join("inside").exists());
+    assert_eq!(listing(&mount)?, vec!["dir"]);
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn ownership_and_permissions() -> Result<(), Box<dyn std::error::Error>> {
+    use rust_decofs::{IdMap, Ownership};
+    use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+    let source = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("etc"))?;
+    // The source's root user had uid 4242, and the host's has uid 0.
+    fs::write(source.path().join("etc").join("passwd"), "root:x:4242:4242:root:/root:/bin/sh\n")?;
+    fs::write(source.path().join("file"), "")?;
+    chown(source.path().join("file"), Some(4242), Some(4343))?;
+    fs::set_permissions(source.path().join("file"), fs::Permissions::from_mode(0o4750))?;
+    let mount_with = |ownership| TestMount::new(source.path(), |builder| builder
+        .ownership(ownership));
+    let owner = |mount: &TestMount| fs::metadata(mount.path().join("file")).map(|attr| (attr.uid(), attr.gid()));
+
+    let mount = mount_with(Ownership::Source)?;
+    let attr = fs::metadata(mount.path().join("file"))?;
+    assert!(attr.is_file());
+    assert_eq!(attr.mode() & 0o7777, 0o4750);
+    assert_eq!(owner(&mount)?, (4242, 4343));
+    mount.unmount()?;
+
+    let mount = mount_with(Ownership::Squash(65534, 65534))?;
+    assert_eq!(owner(&mount)?, (65534, 65534));
+    mount.unmount()?;
+
+    let mount = mount_with(Ownership::Passwd(IdMap { users: Default::default(), groups: vec![(4343, 100)].into_iter().collect() }))?;
+    assert_eq!(owner(&mount)?, (0, 100));
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn immutable_files_are_not_deleted() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::io::AsRawFd;
+    let set_flags = |path: &std::path::Path, flags: libc::c_int| -> std::io::Result<()> {
+        let file = fs::File::open(path)?;
+        match unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error())
+        }
+    };
+    let source = tempfile::tempdir()?;
+    fs::write(source.path().join("file"), "")?;
+    // FS_IMMUTABLE_FL, as set by chattr +i; skipped without CAP_LINUX_IMMUTABLE, or on a file
+    // system without inode flags (tmpfs, overlay).
+    match set_flags(&source.path().join("file"), 0x10) {
+        Err(ref e) if [Some(EPERM), Some(libc::ENOTTY), Some(libc::EOPNOTSUPP)].contains(&e.raw_os_error()) => {
+            eprintln!("skipped: the immutable flag cannot be set here ({})", e);
+            return Ok(());
+        },
+        result => result?
+    }
+    // Denied by the policy, rather than failing with EPERM on the source.
+    let settings = rust_decofs::Settings { deny_errno: libc::EROFS, ..Default::default() };
+    let mount = TestMount::new(source.path(), |builder| builder
+        .settings(std::sync::Arc::new(std::sync::RwLock::new(settings))))?;
+    assert_eq!(fs::remove_file(mount.path().join("file")).unwrap_err().raw_os_error(), Some(libc::EROFS));
+    assert!(source.path().join("file").exists());
+    set_flags(&source.path().join("file"), 0)?;
+    fs::remove_file(mount.path().join("file"))?;
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn source_changes_are_watched() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    let source = tempfile::tempdir()?;
+    fs::create_dir(source.path().join("dir"))?;
+    fs::write(source.path().join("dir/file"), "old")?;
+    fs::write(source.path().join("dir/other"), "other")?;
+    fs::set_permissions(source.path().join("dir/other"), fs::Permissions::from_mode(0o644))?;
+    // Cached for longer than the test runs, so that changes only appear if invalidated.
+    let mount = TestMount::new(source.path(), |builder| builder
+        .ttl(time::Duration::from_secs(60))
+        .watch(true))?;
+    let mode = |path| fs::metadata(mount.path().join(path)).map(|attr| attr.permissions().mode() & 0o777).ok();
+    assert_eq!(fs::read_to_string(mount.path().join("dir/file"))?, "old");
+    assert_eq!(mode("dir/other"), Some(0o644));
+
+    // The file's inode would otherwise still be reached by its old name, as it follows renames.
+    fs::rename(source.path().join("dir/file"), source.path().join("dir/moved"))?;
+    fs::set_permissions(source.path().join("dir/other"), fs::Permissions::from_mode(0o600))?;
+    fs::create_dir(source.path().join("dir/sub"))?;
+    let start = time::Instant::now();
+    while (mount.path().join("dir/file").exists() || mode("dir/other") != Some(0o600)) && start.elapsed() < time::Duration::from_secs(5) {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    assert!(!mount.path().join("dir/file").exists());
+    assert_eq!(fs::read_to_string(mount.path().join("dir/moved"))?, "old");
+    assert_eq!(mode("dir/other"), Some(0o600));
+    assert!(mount.path().join("dir/sub").is_dir());
+    assert_eq!(mount.stats().source_changes(), 4);
+
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn failed_requests_do_not_stop_the_mount() -> Result<(), Box<dyn std::error::Error>> {
+    use rust_decofs::{Attributes, Decision, DefaultPolicy, Operation, OperationPolicy, Requester};
+
+    /// Fails on looking up one file, as a bug in serving it would.
+    #[derive(Debug)]
+    struct Broken;
+    impl OperationPolicy for Broken {
+        fn check(&self, operation: Operation, path: &Path, attributes: Option<&Attributes>, requester: &Requester) -> Decision {
+            if path.ends_with("broken") {
+                panic!("cannot serve {:?}", path);
+            }
+            DefaultPolicy.check(operation, path, attributes, requester)
+        }
+    }
+
+    let source = tempfile::tempdir()?;
+    fs::write(source.path().join("broken"), "")?;
+    fs::write(source.path().join("file"), "fine")?;
+    let mount = TestMount::new(source.path(), |builder| builder
+        .policy(Broken)
+        .threads(1))?;
+
+    for _ in 0..2 {
+        assert_eq!(fs::metadata(mount.path().join("broken")).unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(fs::read_to_string(mount.path().join("file"))?, "fine");
+    }
+    mount.unmount()?;
+    Ok(())
+}
+
+#[test]
+fn reports_missing_arguments() -> Result<(), Box<dyn std::error::Error>> {
+    Command::cargo_bin("rust-decofs")?
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("USAGE"));
+    Ok(())
+}
+
+#[test]
+fn reports_missing_source() -> Result<(), Box<dyn std::error::Error>> {
+    let mountpoint = tempfile::tempdir()?;
+    Command::cargo_bin("rust-decofs")?
+        .arg(mountpoint.path())
+        .arg(mountpoint.path().join("missing"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("decofs: source").and(predicate::str::contains("panicked").not()));
+    Ok(())
+}
+
+#[test]
+fn daemon_unmounts_on_sigterm() -> Result<(), Box<dyn std::error::Error>> {
+    let mountpoint = tempfile::tempdir()?;
+    let source = tempfile::tempdir()?;
+    let pidfile = source.path().join("decofs.pid");
+    fs::write(source.path().join("read"), "world")?;
+    Command::cargo_bin("rust-decofs")?
+        .arg("--daemon")
+        .arg("--pidfile").arg(&pidfile)
+        .arg(mountpoint.path())
+        .arg(source.path())
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(mountpoint.path().join("read"))?, "world");
+
+    let pid = fs::read_to_string(&pidfile)?;
+    Command::new("kill").arg("-TERM").arg(pid.trim()).assert().success();
+    for _ in 0..50 {
+        if !pidfile.exists() {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    assert!(!pidfile.exists());
+    assert!(!mountpoint.path().join("read").exists());
+    Ok(())
+}
+
+#[test]
+fn config_reloads_on_sighup() -> Result<(), Box<dyn std::error::Error>> {
+    let mountpoint = tempfile::tempdir()?;
+    let source = tempfile::tempdir()?;
+    let state = tempfile::tempdir()?;
+    let config = state.path().join("decofs.toml");
+    let pidfile = state.path().join("decofs.pid");
+    fs::write(&config, "ttl = 0.0\n[allow]\nunlink = false\n")?;
+    fs::write(source.path().join("delete"), "world")?;
+    Command::cargo_bin("rust-decofs")?
+        .arg("--daemon")
+        .arg("--config").arg(&config)
+        .arg("--pidfile").arg(&pidfile)
+        .arg(mountpoint.path())
+        .arg(source.path())
+        .assert()
+        .success();
+    let pid = fs::read_to_string(&pidfile)?;
+
+    match fs::remove_file(mountpoint.path().join("delete")) {
+        Err(ref e) if e.raw_os_error() == Some(EPERM) => (),
+        r => panic!("expected EPERM, got {:?}", r)
+    };
+
+    fs::write(&config, "ttl = 0.0\n[allow]\nunlink = true\n")?;
+    Command::new("kill").arg("-HUP").arg(pid.trim()).assert().success();
+    thread::sleep(time::Duration::from_millis(200));
+    let actual = fs::remove_file(mountpoint.path().join("delete"));
+
+    Command::new("kill").arg("-TERM").arg(pid.trim()).assert().success();
+    for _ in 0..50 {
+        if !pidfile.exists() {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    actual?;
+    assert!(!source.path().join("delete").exists());
+    Ok(())
+}
+
+#[test]
+fn mount_helper_mounts_with_fstab_options() -> Result<(), Box<dyn std::error::Error>> {
+    let mountpoint = tempfile::tempdir()?;
+    let source = tempfile::tempdir()?;
+    let state = tempfile::tempdir()?;
+    let pidfile = state.path().join("decofs.pid");
+    fs::write(source.path().join("delete"), "world")?;
+    Command::cargo_bin("mount_decofs")?
+        .arg(source.path())
+        .arg(mountpoint.path())
+        .arg("-n")
+        .arg("-o").arg(format!("rw,noauto,x-systemd.automount,allow_other,policy=readonly,ttl=0,pidfile={}", pidfile.display()))
+        .assert()
+        .success();
+    let pid = fs::read_to_string(&pidfile)?;
+    let actual = fs::remove_file(mountpoint.path().join("delete"));
+
+    rust_decofs::unmount(mountpoint.path())?;
+    for _ in 0..50 {
+        if !pidfile.exists() {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    assert!(!pidfile.exists(), "mount.decofs {} did not exit", pid.trim());
+    match actual {
+        Err(ref e) if e.raw_os_error() == Some(EPERM) => (),
+        r => panic!("expected EPERM, got {:?}", r)
     };
+    assert!(source.path().join("delete").exists());
     Ok(())
 }